@@ -0,0 +1,670 @@
+use fibers::time::timer;
+use futures::{Async, Future, Poll};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use pool::{self, RpcClientPoolHandle};
+use {Error, ErrorKind, Procedure, ResponseMetadata};
+
+const VIRTUAL_NODES: usize = 100;
+
+/// Load balancing strategy of `BalancedRpcClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Selects the addresses in turn.
+    RoundRobin,
+
+    /// Selects the address which has the least in-flight calls issued by the client.
+    LeastInFlight,
+
+    /// Selects the address by consistent hashing of the key given to `call_with_key`.
+    ///
+    /// If a key is not given (i.e., `call`), this behaves like `RoundRobin`.
+    ConsistentHash,
+}
+
+/// Hedging policy of `BalancedRpcClient`.
+///
+/// If no response arrives within the delay, the same request is sent to another address
+/// and the first successful response is taken. The other calls are canceled.
+///
+/// Errors and responses with 5xx or retryable status codes are regarded as failed.
+/// If all the in-flight calls have failed, the request is sent to another address immediately.
+/// If the last attempt fails, its result (the error or the response) is returned as is.
+#[derive(Debug, Clone)]
+pub struct HedgingPolicy {
+    delay: Duration,
+    max_attempts: usize,
+    retryable_statuses: Vec<u16>,
+}
+impl HedgingPolicy {
+    /// Makes a new `HedgingPolicy` instance.
+    ///
+    /// The default maximum number of attempts is `2`.
+    pub fn new(delay: Duration) -> Self {
+        HedgingPolicy {
+            delay,
+            max_attempts: 2,
+            retryable_statuses: Vec::new(),
+        }
+    }
+
+    /// Sets the delay before sending the request to another address.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Sets the maximum number of attempts (including the first one).
+    pub fn set_max_attempts(&mut self, count: usize) {
+        self.max_attempts = count;
+    }
+
+    /// Sets the HTTP status codes of responses which should be regarded as failed
+    /// in addition to 5xx (e.g., `429`).
+    ///
+    /// The default value is empty.
+    pub fn set_retryable_statuses(&mut self, statuses: Vec<u16>) {
+        self.retryable_statuses = statuses;
+    }
+
+    fn is_retryable_status(&self, status: u16) -> bool {
+        status >= 500 || self.retryable_statuses.contains(&status)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    addrs: Vec<SocketAddr>,
+    ring: BTreeMap<u64, SocketAddr>,
+    next: usize,
+    in_flight: HashMap<SocketAddr, usize>,
+}
+impl State {
+    fn set_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.ring.clear();
+        for addr in &addrs {
+            for i in 0..VIRTUAL_NODES {
+                let node = format!("{}#{}", addr, i);
+                self.ring.insert(hash_bytes(node.as_bytes()), *addr);
+            }
+        }
+        self.addrs = addrs;
+    }
+}
+
+/// RPC client which balances calls across multiple server addresses.
+///
+/// The calls are issued through an `RpcClientPool`,
+/// and the addresses blacklisted by the pool are skipped.
+///
+/// The clones of a client share the address set and the in-flight counts.
+#[derive(Debug, Clone)]
+pub struct BalancedRpcClient {
+    handle: RpcClientPoolHandle,
+    strategy: BalanceStrategy,
+    hedging_policy: Option<HedgingPolicy>,
+    state: Arc<Mutex<State>>,
+}
+impl BalancedRpcClient {
+    pub(crate) fn new(
+        handle: RpcClientPoolHandle,
+        addrs: Vec<SocketAddr>,
+        strategy: BalanceStrategy,
+    ) -> Self {
+        let mut state = State::default();
+        state.set_addrs(addrs);
+        BalancedRpcClient {
+            handle,
+            strategy,
+            hedging_policy: None,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Returns the addresses of the servers.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.state
+            .lock()
+            .map(|state| state.addrs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the addresses of the servers.
+    ///
+    /// The calls in progress are not affected.
+    pub fn set_addrs(&self, addrs: Vec<SocketAddr>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.set_addrs(addrs);
+        }
+    }
+
+    /// Sets the hedging policy used by `call_hedged` and `call_hedged_with_key`.
+    pub fn set_hedging_policy(&mut self, policy: HedgingPolicy) {
+        self.hedging_policy = Some(policy);
+    }
+
+    /// Issues an RPC request to one of the servers.
+    pub fn call<P>(&self, request: P::Request) -> BalancedCall<P>
+    where
+        P: Procedure,
+    {
+        self.call_inner::<P>(None, request)
+    }
+
+    /// Issues an RPC request to the server selected by `key`.
+    ///
+    /// The requests with the same key are issued to the same server
+    /// as long as the address set does not change (`BalanceStrategy::ConsistentHash` only).
+    ///
+    /// The keys are hashed by FNV-1a, so the mapping is stable across processes and builds
+    /// as long as the `Hash` implementation of `K` does not change.
+    pub fn call_with_key<P, K>(&self, key: &K, request: P::Request) -> BalancedCall<P>
+    where
+        P: Procedure,
+        K: Hash + ?Sized,
+    {
+        self.call_inner::<P>(Some(hash(key)), request)
+    }
+
+    /// Issues an RPC request which is hedged according to the hedging policy
+    /// (see `set_hedging_policy`).
+    ///
+    /// Requests of non idempotent procedures (see `Procedure::is_idempotent`)
+    /// and requests without a hedging policy are never hedged.
+    pub fn call_hedged<P>(&self, request: P::Request) -> HedgedCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+    {
+        HedgedCall::new(self.clone(), None, request)
+    }
+
+    /// Issues an RPC request to the server selected by `key`
+    /// (and to the following servers if the request is hedged).
+    ///
+    /// See also `call_with_key` and `call_hedged`.
+    pub fn call_hedged_with_key<P, K>(&self, key: &K, request: P::Request) -> HedgedCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+        K: Hash + ?Sized,
+    {
+        HedgedCall::new(self.clone(), Some(hash(key)), request)
+    }
+
+    fn call_inner<P>(&self, key: Option<u64>, request: P::Request) -> BalancedCall<P>
+    where
+        P: Procedure,
+    {
+        self.call_excluding::<P>(key, request, &[])
+    }
+
+    fn call_excluding<P>(
+        &self,
+        key: Option<u64>,
+        request: P::Request,
+        exclude: &[SocketAddr],
+    ) -> BalancedCall<P>
+    where
+        P: Procedure,
+    {
+        match track!(self.select(key, exclude)) {
+            Err(e) => BalancedCall {
+                inner: Err(Some(e)),
+            },
+            Ok(addr) => {
+                let call = self.handle.client(addr).call::<P>(request);
+                let guard = InFlightGuard {
+                    addr,
+                    state: self.state.clone(),
+                };
+                BalancedCall {
+                    inner: Ok((call, guard)),
+                }
+            }
+        }
+    }
+
+    fn select(&self, key: Option<u64>, exclude: &[SocketAddr]) -> Result<SocketAddr, Error> {
+        let mut state = track!(self
+            .state
+            .lock()
+            .map_err(|e| Error::from(ErrorKind::Other.cause(e.to_string()))))?;
+        let handle = &self.handle;
+        let candidates = state
+            .addrs
+            .iter()
+            .cloned()
+            .filter(|addr| !exclude.contains(addr) && !handle.is_blacklisted(*addr))
+            .collect::<Vec<_>>();
+        track_assert!(
+            !candidates.is_empty(),
+            ErrorKind::Other,
+            "No available address: addrs={:?}, exclude={:?}",
+            state.addrs,
+            exclude
+        );
+
+        let addr = match (self.strategy, key) {
+            (BalanceStrategy::ConsistentHash, Some(key)) => state
+                .ring
+                .range(key..)
+                .chain(state.ring.range(..key))
+                .map(|(_, addr)| *addr)
+                .find(|addr| candidates.contains(addr))
+                .expect("Never fails"),
+            (BalanceStrategy::LeastInFlight, _) => {
+                let start = state.next;
+                let in_flight = &state.in_flight;
+                (0..candidates.len())
+                    .map(|i| candidates[(start + i) % candidates.len()])
+                    .min_by_key(|addr| in_flight.get(addr).cloned().unwrap_or(0))
+                    .expect("Never fails")
+            }
+            _ => candidates[state.next % candidates.len()],
+        };
+        state.next = state.next.wrapping_add(1);
+        *state.in_flight.entry(addr).or_insert(0) += 1;
+        Ok(addr)
+    }
+}
+
+fn hash<T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut hasher = FnvHasher::default();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike `DefaultHasher`, the algorithm is fixed,
+/// so the hash ring does not change between processes (and Rust releases).
+/// Integers are written in little-endian (and `usize` as `u64`) to be independent of the platform.
+struct FnvHasher(u64);
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+#[derive(Debug)]
+struct InFlightGuard {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(count) = state.in_flight.get_mut(&self.addr) {
+                *count -= 1;
+            }
+            if state.in_flight.get(&self.addr) == Some(&0) {
+                state.in_flight.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// A `Future` which represents an RPC invocation issued by `BalancedRpcClient`.
+pub struct BalancedCall<P: Procedure> {
+    inner: Result<(pool::Call<P>, InFlightGuard), Option<Error>>,
+}
+impl<P> Future for BalancedCall<P>
+where
+    P: Procedure,
+{
+    type Item = P::Response;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track!(self.poll_with_metadata())?.map(|(response, _)| response))
+    }
+}
+impl<P: Procedure> BalancedCall<P> {
+    fn addr(&self) -> Option<SocketAddr> {
+        self.inner.as_ref().ok().map(|(_, guard)| guard.addr)
+    }
+
+    fn poll_with_metadata(&mut self) -> Poll<(P::Response, ResponseMetadata), Error> {
+        match self.inner {
+            Err(ref mut e) => Err(e.take().expect("Cannot poll BalancedCall twice")),
+            Ok((ref mut call, _)) => track!(call.poll_with_metadata()),
+        }
+    }
+}
+
+/// A `Future` which represents a hedged RPC invocation issued by `BalancedRpcClient`.
+pub struct HedgedCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    client: BalancedRpcClient,
+    key: Option<u64>,
+    request: P::Request,
+    max_attempts: usize,
+    attempts: usize,
+    addrs: Vec<SocketAddr>,
+    calls: Vec<BalancedCall<P>>,
+    policy: Option<HedgingPolicy>,
+    timeout: Option<timer::Timeout>,
+    last_failure: Option<Result<P::Response, Error>>,
+}
+impl<P> HedgedCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    fn new(client: BalancedRpcClient, key: Option<u64>, request: P::Request) -> Self {
+        let policy = client.hedging_policy.clone().filter(|_| P::is_idempotent());
+        let max_attempts = policy.as_ref().map_or(1, |p| p.max_attempts.max(1));
+        let timeout = policy.as_ref().map(|p| timer::timeout(p.delay));
+        let mut this = HedgedCall {
+            client,
+            key,
+            request,
+            max_attempts,
+            attempts: 0,
+            addrs: Vec::new(),
+            calls: Vec::new(),
+            policy,
+            timeout,
+            last_failure: None,
+        };
+        this.issue();
+        this
+    }
+
+    fn issue(&mut self) {
+        self.attempts += 1;
+        let call = self
+            .client
+            .call_excluding::<P>(self.key, self.request.clone(), &self.addrs);
+        if let Some(addr) = call.addr() {
+            self.addrs.push(addr);
+        }
+        self.calls.push(call);
+    }
+
+    fn can_issue(&self) -> bool {
+        self.attempts < self.max_attempts
+    }
+
+    fn is_retryable_status(&self, status: u16) -> bool {
+        self.policy
+            .as_ref()
+            .is_some_and(|p| p.is_retryable_status(status))
+    }
+}
+impl<P> Future for HedgedCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    type Item = P::Response;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut i = 0;
+            while i < self.calls.len() {
+                match self.calls[i].poll_with_metadata() {
+                    Ok(Async::NotReady) => i += 1,
+                    Ok(Async::Ready((response, metadata))) => {
+                        if !self.is_retryable_status(metadata.status()) {
+                            return Ok(Async::Ready(response));
+                        }
+                        self.calls.swap_remove(i);
+                        self.last_failure = Some(Ok(response));
+                    }
+                    Err(e) => {
+                        self.calls.swap_remove(i);
+                        self.last_failure = Some(Err(e));
+                    }
+                }
+            }
+            if !self.can_issue() {
+                if !self.calls.is_empty() {
+                    return Ok(Async::NotReady);
+                }
+                match self.last_failure.take().expect("Never fails") {
+                    Ok(response) => return Ok(Async::Ready(response)),
+                    Err(e) => return Err(track!(e, "attempts={}", self.attempts)),
+                }
+            }
+
+            // NOTE: If all the in-flight calls have failed, the next call is issued immediately.
+            let expired = match self.timeout {
+                Some(ref mut timeout) => track!(timeout.poll().map_err(Error::from))?.is_ready(),
+                None => false,
+            };
+            if !expired && !self.calls.is_empty() {
+                return Ok(Async::NotReady);
+            }
+            if let Some(ref policy) = self.policy {
+                self.timeout = Some(timer::timeout(policy.delay));
+            }
+            self.issue();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    use super::*;
+    use pool::RpcClientPool;
+    use test_util::*;
+
+    #[test]
+    fn it_works() {
+        let mut builder = server_builder();
+        builder.register(NamedHelloHandler("foo"), Hello).unwrap();
+        let addr0 = spawn_server(builder).addr;
+        let mut builder = server_builder();
+        builder.register(NamedHelloHandler("bar"), Hello).unwrap();
+        let addr1 = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let request = || hello("world");
+        let run = |executor: &mut InPlaceExecutor, call: BalancedCall<Hello>| {
+            let monitor = executor.spawn_monitor(call);
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::Ok { body } => String::from_utf8(body).unwrap(),
+                r => panic!("Unexpected response: {:?}", r),
+            }
+        };
+
+        // Round-robin
+        let client = handle.balanced_client(vec![addr0, addr1], BalanceStrategy::RoundRobin);
+        let names = (0..4)
+            .map(|_| run(&mut executor, client.call::<Hello>(request())))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["foo", "bar", "foo", "bar"]);
+
+        // Blacklisted addresses are skipped.
+        handle.add_to_blacklist(addr0, Duration::from_secs(60));
+        executor.run_future(handle.stats()).unwrap().unwrap();
+        let names = (0..2)
+            .map(|_| run(&mut executor, client.call::<Hello>(request())))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["bar", "bar"]);
+        handle.clear_blacklist();
+        executor.run_future(handle.stats()).unwrap().unwrap();
+
+        // Least in-flight
+        let client = handle.balanced_client(vec![addr0, addr1], BalanceStrategy::LeastInFlight);
+        let call0 = client.call::<Hello>(request());
+        let call1 = client.call::<Hello>(request());
+        let mut names = vec![run(&mut executor, call0), run(&mut executor, call1)];
+        names.sort();
+        assert_eq!(names, ["bar", "foo"]);
+
+        // Consistent hash
+        let client = handle.balanced_client(vec![addr0, addr1], BalanceStrategy::ConsistentHash);
+        for key in 0..10 {
+            let name = run(
+                &mut executor,
+                client.call_with_key::<Hello, _>(&key, request()),
+            );
+            for _ in 0..3 {
+                let call = client.call_with_key::<Hello, _>(&key, request());
+                assert_eq!(run(&mut executor, call), name);
+            }
+        }
+
+        // Updating the address set
+        client.set_addrs(vec![addr1]);
+        assert_eq!(client.addrs(), [addr1]);
+        for key in 0..10 {
+            let call = client.call_with_key::<Hello, _>(&key, request());
+            assert_eq!(run(&mut executor, call), "bar");
+        }
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // Test vectors of FNV-1a (64-bit).
+        assert_eq!(hash_bytes(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash_bytes(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash_bytes(b"foobar"), 0x8594_4171_f739_67e8);
+
+        let addrs = (0..3)
+            .map(|i| SocketAddr::from(([10, 0, 0, i], 80)))
+            .collect::<Vec<_>>();
+        let client = RpcClientPool::new()
+            .handle()
+            .balanced_client(addrs.clone(), BalanceStrategy::ConsistentHash);
+        let select = |key: u64| client.select(Some(hash(&key)), &[]).unwrap();
+        assert_eq!(hash(&42u64), 0xff3a_dd6b_3789_daef);
+        assert_eq!(select(0), addrs[2]);
+        assert_eq!(select(1), addrs[1]);
+        assert_eq!(select(2), addrs[0]);
+    }
+
+    #[test]
+    fn hedging_works() {
+        let mut builder = server_builder();
+        builder.register(SlowHelloHandler, Hello).unwrap();
+        let slow_addr = spawn_server(builder).addr;
+        let mut builder = server_builder();
+        builder.register(NamedHelloHandler("fast"), Hello).unwrap();
+        let fast_addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let mut client =
+            handle.balanced_client(vec![slow_addr, fast_addr], BalanceStrategy::RoundRobin);
+        client.set_hedging_policy(HedgingPolicy::new(Duration::from_millis(50)));
+        let request = hello("slow");
+        let monitor = executor.spawn_monitor(client.call_hedged::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"fast"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn hedging_fast_failure_works() {
+        let mut builder = server_builder();
+        builder
+            .register(FlakyHelloHandler::new(usize::MAX), Hello)
+            .unwrap();
+        let failing_addr = spawn_server(builder).addr;
+        let mut builder = server_builder();
+        let delay = Duration::from_millis(50);
+        builder.register(DelayedHelloHandler(delay), Hello).unwrap();
+        let delayed_addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        // The fast `503` response is ignored, and the request is sent to the other address.
+        let mut client = handle.balanced_client(
+            vec![failing_addr, delayed_addr],
+            BalanceStrategy::RoundRobin,
+        );
+        client.set_hedging_policy(HedgingPolicy::new(Duration::from_millis(100)));
+        let monitor = executor.spawn_monitor(client.call_hedged::<Hello>(hello("foo")));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"Hello foo"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn hedging_all_failures_work() {
+        let handler0 = FlakyHelloHandler::new(usize::MAX);
+        let mut builder = server_builder();
+        builder.register(handler0.clone(), Hello).unwrap();
+        let addr0 = spawn_server(builder).addr;
+        let handler1 = FlakyHelloHandler::new(usize::MAX);
+        let mut builder = server_builder();
+        builder.register(handler1.clone(), Hello).unwrap();
+        let addr1 = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        // The request is re-issued without waiting for the delay,
+        // and the last response is returned as is.
+        let delay = Duration::from_secs(1);
+        let mut client = handle.balanced_client(vec![addr0, addr1], BalanceStrategy::RoundRobin);
+        client.set_hedging_policy(HedgingPolicy::new(delay));
+        let started_at = Instant::now();
+        let monitor = executor.spawn_monitor(client.call_hedged::<Hello>(hello("foo")));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::ServiceUnavailable { .. } => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        assert!(started_at.elapsed() < delay);
+        assert_eq!(handler0.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(handler1.calls.load(Ordering::SeqCst), 1);
+    }
+}
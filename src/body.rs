@@ -0,0 +1,133 @@
+use fibers::net::TcpStream;
+use miasht;
+use miasht::builtin::io::IoExt;
+use miasht::server::Request;
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+
+use trackable::error::ErrorKindExt;
+
+use {Error, ErrorKind, Result};
+
+/// A reader for the body of an HTTP request.
+///
+/// If the maximum body size is specified
+/// (see `RpcServerBuilder::set_max_body_size` and `ProcedureOptions::set_max_body_size`),
+/// reading more bytes than the limit results in an error and
+/// the server will respond with `413 Payload Too Large`.
+#[derive(Debug)]
+pub struct BodyReader {
+    inner: miasht::builtin::io::BodyReader<Request<TcpStream>>,
+    read_bytes: u64,
+    max_size: Option<u64>,
+}
+impl BodyReader {
+    pub(crate) fn new(request: Request<TcpStream>, max_size: Option<u64>) -> Result<Self> {
+        let inner = track!(request.into_body_reader().map_err(Error::from))?;
+        let body = BodyReader {
+            inner,
+            read_bytes: 0,
+            max_size,
+        };
+        if let (Some(length), Some(max_size)) = (body.content_length(), max_size) {
+            if length > max_size {
+                let e = io::Error::from(PayloadTooLarge { max_size });
+                return Err(track!(Error::from(ErrorKind::Invalid.cause(e))));
+            }
+        }
+        Ok(body)
+    }
+
+    /// Returns the value of the `Content-Length` header of the request.
+    ///
+    /// If the request is encoded by the chunked transfer coding, this will return `None`.
+    pub fn content_length(&self) -> Option<u64> {
+        if let miasht::builtin::io::BodyReader::FixedLength(ref r) = self.inner {
+            Some(r.limit())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the maximum size of the body.
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Converts into the underlying HTTP request.
+    pub fn into_inner(self) -> Request<TcpStream> {
+        self.inner.into_inner()
+    }
+}
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            // NOTE: One extra byte is read to detect the excess.
+            let remaining = max_size.saturating_add(1) - self.read_bytes;
+            let len = if (buf.len() as u64) < remaining {
+                buf.len()
+            } else {
+                remaining as usize
+            };
+            let read_size = self.inner.read(&mut buf[..len])?;
+            self.read_bytes += read_size as u64;
+            if self.read_bytes > max_size {
+                return Err(io::Error::from(PayloadTooLarge { max_size }));
+            }
+            Ok(read_size)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+/// The cause of errors which indicate that the body of a request exceeds the limit.
+#[derive(Debug)]
+pub(crate) struct PayloadTooLarge {
+    max_size: u64,
+}
+impl PayloadTooLarge {
+    /// Returns `true` if the error is caused by an excessive request body.
+    pub fn is_cause_of(e: &Error) -> bool {
+        e.concrete_cause::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|e| e.is::<PayloadTooLarge>())
+    }
+}
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The request body exceeds the limit ({} bytes)",
+            self.max_size
+        )
+    }
+}
+impl error::Error for PayloadTooLarge {}
+impl From<PayloadTooLarge> for io::Error {
+    fn from(f: PayloadTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, f)
+    }
+}
+
+/// The cause of errors which indicate that reading a request body timed out.
+#[derive(Debug)]
+pub(crate) struct ReadBodyTimeout;
+impl ReadBodyTimeout {
+    /// Returns `true` if the error is caused by the timeout of reading a request body.
+    pub fn is_cause_of(e: &Error) -> bool {
+        e.concrete_cause::<ReadBodyTimeout>().is_some()
+    }
+}
+impl fmt::Display for ReadBodyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reading the request body timed out")
+    }
+}
+impl error::Error for ReadBodyTimeout {}
+impl From<ReadBodyTimeout> for Error {
+    fn from(f: ReadBodyTimeout) -> Self {
+        ErrorKind::Timeout.cause(f).into()
+    }
+}
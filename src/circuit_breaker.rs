@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Circuit breaker policy of `RpcClientPool`.
+///
+/// The circuit of an address is opened (i.e., calls to the address fail immediately
+/// with `ErrorKind::CircuitOpen`) if the failure rate of the recent calls exceeds the threshold.
+/// A call is regarded as failed if it results in an error, a 5xx response,
+/// or takes longer than the slow call duration.
+///
+/// After the open duration has elapsed, the circuit becomes half-open and
+/// a limited number of trial calls are allowed.
+/// If all of them succeed, the circuit is closed again; otherwise it is opened again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    window_size: usize,
+    min_calls: usize,
+    failure_rate_threshold: f64,
+    slow_call_duration: Option<Duration>,
+    open_duration: Duration,
+    half_open_calls: usize,
+}
+impl CircuitBreakerPolicy {
+    /// Makes a new `CircuitBreakerPolicy` instance with the default settings.
+    ///
+    /// The defaults are as follows:
+    ///
+    /// - window size: `20`
+    /// - min calls: `10`
+    /// - failure rate threshold: `0.5`
+    /// - slow call duration: none
+    /// - open duration: `30s`
+    /// - half-open calls: `1`
+    pub fn new() -> Self {
+        CircuitBreakerPolicy {
+            window_size: 20,
+            min_calls: 10,
+            failure_rate_threshold: 0.5,
+            slow_call_duration: None,
+            open_duration: Duration::from_secs(30),
+            half_open_calls: 1,
+        }
+    }
+
+    /// Sets the number of the recent calls used to calculate the failure rate.
+    pub fn set_window_size(&mut self, size: usize) {
+        self.window_size = size;
+    }
+
+    /// Sets the minimum number of calls required before the circuit can be opened.
+    pub fn set_min_calls(&mut self, count: usize) {
+        self.min_calls = count;
+    }
+
+    /// Sets the failure rate (`0.0` to `1.0`) at which the circuit is opened.
+    pub fn set_failure_rate_threshold(&mut self, rate: f64) {
+        self.failure_rate_threshold = rate;
+    }
+
+    /// Sets the duration beyond which a call is regarded as failed.
+    pub fn set_slow_call_duration(&mut self, duration: Duration) {
+        self.slow_call_duration = Some(duration);
+    }
+
+    /// Sets the duration for which the circuit stays open before becoming half-open.
+    pub fn set_open_duration(&mut self, duration: Duration) {
+        self.open_duration = duration;
+    }
+
+    /// Sets the number of trial calls allowed in the half-open state.
+    ///
+    /// `0` is treated as `1`.
+    pub fn set_half_open_calls(&mut self, count: usize) {
+        self.half_open_calls = count;
+    }
+
+    pub(crate) fn is_success(&self, status: Option<u16>, elapsed: Duration) -> bool {
+        let is_slow = self.slow_call_duration.is_some_and(|d| elapsed > d);
+        status.is_some_and(|s| s < 500) && !is_slow
+    }
+}
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls are allowed.
+    Closed,
+
+    /// Calls fail immediately.
+    Open,
+
+    /// A limited number of trial calls are allowed.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { window: VecDeque<bool> },
+    Open { until: Instant },
+    HalfOpen { in_flight: usize, successes: usize },
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    state: State,
+}
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        CircuitBreaker {
+            policy,
+            state: State::Closed {
+                window: VecDeque::new(),
+            },
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.state {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns `true` if a call is allowed.
+    ///
+    /// If this returns `true`, the result of the call must be reported
+    /// by `record` or `cancel`.
+    pub fn try_acquire(&mut self) -> bool {
+        if let State::Open { until } = self.state {
+            if Instant::now() < until {
+                return false;
+            }
+            self.state = State::HalfOpen {
+                in_flight: 0,
+                successes: 0,
+            };
+        }
+        let half_open_calls = self.policy.half_open_calls.max(1);
+        match self.state {
+            State::HalfOpen {
+                ref mut in_flight,
+                successes,
+            } => {
+                if *in_flight + successes >= half_open_calls {
+                    return false;
+                }
+                *in_flight += 1;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Records the result of a call.
+    pub fn record(&mut self, success: bool) {
+        let open = State::Open {
+            until: Instant::now() + self.policy.open_duration,
+        };
+        let next = match self.state {
+            State::Closed { ref mut window } => {
+                window.push_back(success);
+                while window.len() > self.policy.window_size {
+                    window.pop_front();
+                }
+                let failures = window.iter().filter(|s| !**s).count();
+                let rate = failures as f64 / window.len() as f64;
+                if window.len() >= self.policy.min_calls
+                    && rate >= self.policy.failure_rate_threshold
+                {
+                    Some(open)
+                } else {
+                    None
+                }
+            }
+            State::Open { .. } => None,
+            State::HalfOpen {
+                ref mut in_flight,
+                ref mut successes,
+            } => {
+                *in_flight = in_flight.saturating_sub(1);
+                if !success {
+                    Some(open)
+                } else {
+                    *successes += 1;
+                    if *successes >= self.policy.half_open_calls.max(1) {
+                        Some(State::Closed {
+                            window: VecDeque::new(),
+                        })
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(next) = next {
+            self.state = next;
+        }
+    }
+
+    /// Records that a call has been canceled (i.e., the result is unknown).
+    pub fn cancel(&mut self) {
+        if let State::HalfOpen {
+            ref mut in_flight, ..
+        } = self.state
+        {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use fibers::sync::oneshot::MonitorError;
+    use fibers::time::timer;
+
+    use pool::RpcClientPool;
+    use test_util::*;
+    use ErrorKind;
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let mut policy = CircuitBreakerPolicy::new();
+        policy.set_window_size(4);
+        policy.set_min_calls(2);
+        policy.set_open_duration(Duration::from_millis(50));
+        let mut breaker = CircuitBreaker::new(policy);
+
+        assert!(breaker.try_acquire());
+        breaker.record(true);
+        assert!(breaker.try_acquire());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(breaker.try_acquire());
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn pool_works() {
+        let mut builder = server_builder();
+        builder.register(FlakyHelloHandler::new(2), Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut policy = CircuitBreakerPolicy::new();
+        policy.set_window_size(2);
+        policy.set_min_calls(2);
+        policy.set_open_duration(Duration::from_millis(100));
+        let mut pool = RpcClientPool::new();
+        pool.set_circuit_breaker_policy(policy);
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let call = |executor: &mut InPlaceExecutor| {
+            let request = hello("world");
+            let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(request));
+            executor.run_future(monitor).unwrap()
+        };
+
+        // Opened
+        for _ in 0..2 {
+            match call(&mut executor) {
+                Ok(HelloResponse::ServiceUnavailable { .. }) => {}
+                r => panic!("Unexpected result: {:?}", r.map_err(|_| ())),
+            }
+        }
+        match call(&mut executor) {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::CircuitOpen),
+            r => panic!("Unexpected result: {:?}", r.map_err(|_| ())),
+        }
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert_eq!(stats.circuits.get(&addr), Some(&CircuitState::Open));
+
+        // Half-opened, then closed
+        let timeout = timer::timeout(Duration::from_millis(150));
+        executor.run_future(timeout).unwrap().unwrap();
+        match call(&mut executor) {
+            Ok(HelloResponse::Ok { .. }) => {}
+            r => panic!("Unexpected result: {:?}", r.map_err(|_| ())),
+        }
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert!(stats.circuits.is_empty());
+    }
+}
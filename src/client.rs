@@ -1,30 +1,142 @@
+use std::io::{self, Read};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use fibers::net::TcpStream;
+use fibers::time::timer::{self, TimerExt};
 use futures::{self, Async, Future, Poll};
 use handy_async::future::Phase;
 use miasht;
 use miasht::builtin::io::IoExt;
 use miasht::builtin::futures::FutureExt;
 use miasht::client::{Connection, Response};
+use miasht::Version;
 use serde::{Deserialize, Serialize};
+use trackable::error::ErrorKindExt;
 
-use Error;
+use {Error, ErrorKind, ResponseMetadata, Result, RetryCall, RetryPolicy};
 use deserializers::RpcResponseDeserializer;
+use metadata::Timings;
 use procedure::Procedure;
+use retry::Caller;
 use serializers::RpcRequestSerializer;
-use types::HttpMethod;
+use types::{EntryPoint, HttpMethod};
 
 type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send + 'static>;
+type IdleConnections = Arc<Mutex<Vec<Connection<TcpStream>>>>;
+
+/// A `Future` which results in a connection and whether it is a reused idle connection.
+pub(crate) type Connect = BoxFuture<(Connection<TcpStream>, bool), Error>;
+
+/// A function which establishes a new connection (i.e., never reuses an idle connection).
+pub(crate) type Reconnect = Box<dyn FnOnce() -> BoxFuture<Connection<TcpStream>, Error> + Send>;
 
 /// RPC Client.
-#[derive(Debug)]
+///
+/// The client keeps the connections to the server alive (i.e., HTTP keep-alive)
+/// and reuses them across calls.
+/// If the server has closed an idle connection, a new connection is established transparently.
+/// If a reused connection turns out to be dead before the response arrives,
+/// the request is sent again once over a new connection.
+///
+/// The clones of a client share the idle connections.
+#[derive(Debug, Clone)]
 pub struct RpcClient {
     server: SocketAddr,
+    base_path: Option<EntryPoint>,
+    idle_connections: IdleConnections,
+    max_idle_connections: usize,
+    options: CallOptions,
+    retry_policy: RetryPolicy,
 }
 impl RpcClient {
     /// Makes an RPC client which will communicate with the `server`.
     pub fn new(server: SocketAddr) -> Self {
-        RpcClient { server }
+        RpcClient {
+            server,
+            base_path: None,
+            idle_connections: Arc::new(Mutex::new(Vec::new())),
+            max_idle_connections: 1,
+            options: CallOptions::new(),
+            retry_policy: RetryPolicy::new(),
+        }
+    }
+
+    /// Sets the default total timeout of a call
+    /// (i.e., from connecting to the server to reading the response body).
+    ///
+    /// If the timeout expires, the call will fail with `ErrorKind::Timeout`.
+    /// The timeout can be overridden for each call by using `CallOptions`.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.options.timeout = Some(timeout);
+    }
+
+    /// Sets the default timeout for reading a response (the head and the body respectively).
+    ///
+    /// If the timeout expires, the call will fail with `ErrorKind::Timeout`.
+    /// The timeout can be overridden for each call by using `CallOptions`.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.options.read_timeout = Some(timeout);
+    }
+
+    /// Sets the default timeout for writing a request.
+    ///
+    /// If the timeout expires, the call will fail with `ErrorKind::Timeout`.
+    /// The timeout can be overridden for each call by using `CallOptions`.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.options.write_timeout = Some(timeout);
+    }
+
+    /// If `true` is specified, the requests of non idempotent procedures are also sent again
+    /// when the reused connections turn out to have been closed by the server.
+    ///
+    /// Note that the server may have processed such a request before closing the connection.
+    /// This can be overridden for each call by using `CallOptions`.
+    ///
+    /// The default value is `false`.
+    pub fn set_resend_non_idempotent(&mut self, enabled: bool) {
+        self.options.resend_non_idempotent = Some(enabled);
+    }
+
+    /// Sets the maximum number of idle connections kept by this client.
+    ///
+    /// If `0` is specified, a new connection is established for every call.
+    ///
+    /// The default value is `1`.
+    pub fn set_max_idle_connections(&mut self, count: usize) {
+        self.max_idle_connections = count;
+        if let Ok(mut idle_connections) = self.idle_connections.lock() {
+            idle_connections.truncate(count);
+        }
+    }
+
+    /// Sets the base path which is prepended to the entry points of procedures.
+    ///
+    /// This is useful for calling procedures mounted under a prefix
+    /// (see `RpcServerBuilder::mount`).
+    ///
+    /// If `base_path` contains non-literal segments (i.e., variables or a tail),
+    /// this will return an `ErrorKind::Invalid` error.
+    pub fn set_base_path(&mut self, base_path: EntryPoint) -> Result<()> {
+        track_assert!(
+            base_path.is_literal(),
+            ErrorKind::Invalid,
+            "A base path must consist of only literal segments: base_path={:?}",
+            base_path
+        );
+        self.base_path = Some(base_path);
+        Ok(())
+    }
+
+    /// Sets the retry policy used by `call_with_retry`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
     }
 
     /// Issues an RPC request and returns the `Future`
@@ -33,19 +145,133 @@ impl RpcClient {
     where
         P: Procedure,
     {
-        let client = miasht::Client::new();
-        let future = Call(CallInner {
-            request: Some(request),
-            phase: Phase::A(Box::new(client.connect(self.server).map_err(Error::from))),
+        self.call_with_options::<P>(request, CallOptions::new())
+    }
+
+    /// Issues an RPC request with the call specific options.
+    pub fn call_with_options<P>(&mut self, request: P::Request, options: CallOptions) -> Call<P>
+    where
+        P: Procedure,
+    {
+        let connect = AcquireConnection {
+            server: self.server,
+            idle_connections: self.idle_connections.clone(),
+            connect: None,
+        };
+        let entry_point = if let Some(ref base_path) = self.base_path {
+            P::entry_point().with_prefix(base_path)
+        } else {
+            P::entry_point()
+        };
+        let server = self.server;
+        let reconnect: Reconnect = Box::new(move || {
+            let future = miasht::Client::new().connect(server);
+            Box::new(future.map_err(|e| track!(Error::from(e))))
         });
-        future
+        let options = options.or(&self.options);
+        let inner = CallInner::new(request, entry_point, Box::new(connect), reconnect, options);
+        Call {
+            inner,
+            idle_connections: self.idle_connections.clone(),
+            max_idle_connections: self.max_idle_connections,
+        }
+    }
+
+    /// Issues an RPC request which will be retried according to the retry policy
+    /// (see `set_retry_policy`).
+    pub fn call_with_retry<P>(&mut self, request: P::Request) -> RetryCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+    {
+        self.call_with_retry_and_options::<P>(request, CallOptions::new())
+    }
+
+    /// Issues an RPC request which will be retried according to the retry policy
+    /// with the call specific options.
+    ///
+    /// The total timeout (if any) is applied to the whole call including retries.
+    pub fn call_with_retry_and_options<P>(
+        &mut self,
+        request: P::Request,
+        options: CallOptions,
+    ) -> RetryCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+    {
+        let options = options.or(&self.options);
+        let policy = self.retry_policy.clone();
+        RetryCall::new(Caller::Client(self.clone()), request, policy, options)
+    }
+}
+
+/// Options for an RPC invocation.
+///
+/// The options which are not set are inherited from the client
+/// (e.g., `RpcClient::set_timeout`).
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    resend_non_idempotent: Option<bool>,
+}
+impl CallOptions {
+    /// Makes a new `CallOptions` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the total timeout of the call.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Sets the timeout for reading the response.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+    }
+
+    /// Sets the timeout for writing the request.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = Some(timeout);
+    }
+
+    /// Sets whether the request is sent again over a new connection
+    /// if the reused connection has been closed by the server,
+    /// even if the procedure is not idempotent.
+    ///
+    /// See also `RpcClient::set_resend_non_idempotent`.
+    pub fn set_resend_non_idempotent(&mut self, enabled: bool) {
+        self.resend_non_idempotent = Some(enabled);
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn or(self, defaults: &CallOptions) -> Self {
+        CallOptions {
+            timeout: self.timeout.or(defaults.timeout),
+            read_timeout: self.read_timeout.or(defaults.read_timeout),
+            write_timeout: self.write_timeout.or(defaults.write_timeout),
+            resend_non_idempotent: self
+                .resend_non_idempotent
+                .or(defaults.resend_non_idempotent),
+        }
     }
 }
 
 /// A `Future` which represents an RPC invocation.
-pub struct Call<P>(CallInner<P>)
+pub struct Call<P>
 where
-    P: Procedure;
+    P: Procedure,
+{
+    inner: CallInner<P>,
+    idle_connections: IdleConnections,
+    max_idle_connections: usize,
+}
 impl<P> Future for Call<P>
 where
     P: Procedure,
@@ -53,57 +279,264 @@ where
     type Item = P::Response;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Async::Ready((response, _)) = track!(self.0.poll())? {
-            Ok(Async::Ready(response))
+        Ok(track!(self.poll_with_metadata())?.map(|(response, _)| response))
+    }
+}
+impl<P> Call<P>
+where
+    P: Procedure,
+{
+    /// Converts into a future which also results in the metadata of the HTTP response.
+    pub fn with_metadata(self) -> CallWithMetadata<P> {
+        CallWithMetadata(self)
+    }
+
+    /// Polls the call and returns the RPC response with the metadata of the HTTP response.
+    pub(crate) fn poll_with_metadata(&mut self) -> Poll<(P::Response, ResponseMetadata), Error> {
+        if let Async::Ready((response, metadata, connection)) = track!(self.inner.poll())? {
+            if let (Some(connection), Ok(mut idle_connections)) =
+                (connection, self.idle_connections.lock())
+            {
+                if idle_connections.len() < self.max_idle_connections {
+                    idle_connections.push(connection);
+                }
+            }
+            Ok(Async::Ready((response, metadata)))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
 
+/// A `Future` which represents an RPC invocation resulting in the response with its metadata.
+pub struct CallWithMetadata<P>(Call<P>)
+where
+    P: Procedure;
+impl<P> Future for CallWithMetadata<P>
+where
+    P: Procedure,
+{
+    type Item = (P::Response, ResponseMetadata);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.0.poll_with_metadata())
+    }
+}
+
+/// A `Future` which acquires an idle connection or establishes a new connection.
+struct AcquireConnection {
+    server: SocketAddr,
+    idle_connections: IdleConnections,
+    connect: Option<miasht::client::Connect>,
+}
+impl Future for AcquireConnection {
+    type Item = (Connection<TcpStream>, bool);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(ref mut connect) = self.connect {
+            let connection = track!(connect.poll().map_err(Error::from))?;
+            return Ok(connection.map(|c| (c, false)));
+        }
+        while let Some(mut connection) = self
+            .idle_connections
+            .lock()
+            .ok()
+            .and_then(|mut idle_connections| idle_connections.pop())
+        {
+            if is_alive(&mut connection) {
+                return Ok(Async::Ready((connection, true)));
+            }
+        }
+        self.connect = Some(miasht::Client::new().connect(self.server));
+        self.poll()
+    }
+}
+
+fn with_timeout<F>(
+    future: F,
+    timeout: Option<Duration>,
+    operation: &'static str,
+) -> BoxFuture<F::Item, Error>
+where
+    F: Future<Error = miasht::Error> + Send + 'static,
+{
+    if let Some(timeout) = timeout {
+        Box::new(future.timeout_after(timeout).map_err(move |e| {
+            if let Some(e) = e {
+                track!(Error::from(e))
+            } else {
+                let e = ErrorKind::Timeout.cause(format!("{} timed out", operation));
+                track!(Error::from(e))
+            }
+        }))
+    } else {
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+}
+
+/// Returns `true` if the idle connection has not been closed by the server.
+pub(crate) fn is_alive(connection: &mut Connection<TcpStream>) -> bool {
+    // NOTE: No bytes should arrive on an idle connection.
+    // Reading `0` bytes means that the server has closed the connection,
+    // and unexpected bytes mean that the connection is in an inconsistent state.
+    let mut buf = [0; 1];
+    match connection.as_mut().stream.read(&mut buf) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(0) => false,
+        Ok(_size) => false,
+    }
+}
+
+/// Returns `true` if the connection can be reused after receiving the response.
+fn is_keep_alive(response: &Response<TcpStream>) -> bool {
+    let connection = response.headers().get("Connection");
+    if response.version() == Version::Http1_0 {
+        connection.is_some_and(|v| v.eq_ignore_ascii_case(b"keep-alive"))
+    } else {
+        !connection.is_some_and(|v| v.eq_ignore_ascii_case(b"close"))
+    }
+}
+
 pub(crate) struct CallInner<P>
 where
     P: Procedure,
 {
-    pub request: Option<P::Request>,
-    pub phase: Phase<
+    request: P::Request,
+    body: Option<Vec<u8>>,
+    entry_point: EntryPoint,
+    options: CallOptions,
+    deadline: Option<timer::Timeout>,
+    reused: bool,
+    reconnect: Option<Reconnect>,
+    request_body_size: usize,
+    timings: Timings,
+    phase: Phase<
+        Connect,
         BoxFuture<Connection<TcpStream>, Error>,
-        BoxFuture<Connection<TcpStream>, miasht::Error>,
-        BoxFuture<Response<TcpStream>, miasht::Error>,
-        BoxFuture<(Response<TcpStream>, Vec<u8>), miasht::Error>,
+        BoxFuture<Response<TcpStream>, Error>,
+        BoxFuture<(Response<TcpStream>, Vec<u8>), Error>,
     >,
 }
+impl<P> CallInner<P>
+where
+    P: Procedure,
+{
+    pub fn new(
+        request: P::Request,
+        entry_point: EntryPoint,
+        connect: Connect,
+        reconnect: Reconnect,
+        options: CallOptions,
+    ) -> Self {
+        let deadline = options.timeout.map(timer::timeout);
+        CallInner {
+            request,
+            body: None,
+            entry_point,
+            options,
+            deadline,
+            reused: false,
+            reconnect: Some(reconnect),
+            request_body_size: 0,
+            timings: Timings::new(),
+            phase: Phase::A(connect),
+        }
+    }
+
+    /// Returns `true` if the request can be sent again if the connection turns out to be stale.
+    fn can_resend(&self) -> bool {
+        let resend_non_idempotent = self.options.resend_non_idempotent == Some(true);
+        self.reused && self.reconnect.is_some() && (P::is_idempotent() || resend_non_idempotent)
+    }
+
+    /// Returns `true` if the request should be sent again over a new connection.
+    ///
+    /// This is the case where a reused connection has been closed by the server
+    /// (e.g., due to the keep-alive timeout) before the response head is received.
+    fn is_stale_connection_error(&self, error: &Error) -> bool {
+        self.can_resend()
+            && error.concrete_cause::<io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::BrokenPipe
+                )
+            })
+    }
+}
 impl<P> Future for CallInner<P>
 where
     P: Procedure,
 {
-    type Item = (P::Response, Connection<TcpStream>);
+    type Item = (P::Response, ResponseMetadata, Option<Connection<TcpStream>>);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(ref mut deadline) = self.deadline {
+            if track!(deadline.poll().map_err(Error::from))?.is_ready() {
+                track_panic!(
+                    ErrorKind::Timeout,
+                    "RPC call timed out: method={}, entry_point={:?}",
+                    P::method(),
+                    self.entry_point
+                );
+            }
+        }
         loop {
-            let next = match track!(self.phase.poll().map_err(Error::from))? {
+            let phase = match self.phase.poll() {
+                Err(Phase::B(e)) | Err(Phase::C(e)) => {
+                    if self.is_stale_connection_error(&e) {
+                        // Sends the request again over a new connection.
+                        let reconnect = self.reconnect.take().expect("Never fails");
+                        self.reused = false;
+                        self.phase = Phase::A(Box::new(reconnect().map(|c| (c, false))));
+                        continue;
+                    }
+                    return Err(track!(e));
+                }
+                Err(e) => return Err(track!(Error::from(e))),
+                Ok(phase) => phase,
+            };
+            let next = match phase {
                 Async::NotReady => return Ok(Async::NotReady),
-                Async::Ready(Phase::A(connection)) => {
+                Async::Ready(Phase::A((connection, reused))) => {
                     // Writes HTTP request.
                     use RpcRequest;
-                    let entry_point = P::entry_point();
-                    let mut rpc_request = self.request.take().expect("Never fail");
+                    self.reused = reused;
+                    self.timings.connected_at = Some(Instant::now());
+                    let entry_point = self.entry_point.clone();
                     let mut ser = RpcRequestSerializer::new(connection, P::method(), entry_point);
-                    track!(rpc_request.serialize(&mut ser))?;
-                    let body = rpc_request.body();
+                    track!(self.request.serialize(&mut ser))?;
+                    let body = match self.body.take() {
+                        Some(body) => body,
+                        None => self.request.body(),
+                    };
+                    if self.can_resend() {
+                        // Keeps the body for sending the request again.
+                        self.body = Some(body.clone());
+                    }
+                    self.request_body_size = body.len();
                     let request = track!(ser.finish(&body))?;
-                    let future: BoxFuture<_, _> =
-                        Box::new(request.write_all_bytes(body).and_then(|r| r));
-                    Phase::B(future)
+                    let future = request.write_all_bytes(body).and_then(|r| r);
+                    Phase::B(with_timeout(
+                        future,
+                        self.options.write_timeout,
+                        "Writing the request",
+                    ))
                 }
                 Async::Ready(Phase::B(connection)) => {
                     // Reads HTTP response (without body).
-                    let future: BoxFuture<_, _> = Box::new(connection.read_response());
-                    Phase::C(future)
+                    let future = connection.read_response();
+                    Phase::C(with_timeout(
+                        future,
+                        self.options.read_timeout,
+                        "Reading the response head",
+                    ))
                 }
                 Async::Ready(Phase::C(response)) => {
                     // Reads HTTP response body.
-                    let future: BoxFuture<_, _> = if P::method() == HttpMethod::Head {
+                    self.timings.first_byte_at = Some(Instant::now());
+                    let future: BoxFuture<_, miasht::Error> = if P::method() == HttpMethod::Head {
                         Box::new(futures::finished((response, Vec::new())))
                     } else {
                         let future = futures::done(response.into_body_reader())
@@ -111,7 +544,11 @@ where
                             .map(|(res, body)| (res.into_inner(), body));
                         Box::new(future)
                     };
-                    Phase::D(future)
+                    Phase::D(with_timeout(
+                        future,
+                        self.options.read_timeout,
+                        "Reading the response body",
+                    ))
                 }
                 Async::Ready(Phase::D((response, body))) => {
                     // Converts from HTTP response to RPC response.
@@ -120,8 +557,20 @@ where
                         let mut deserializer = RpcResponseDeserializer::new(&response);
                         track!(P::Response::deserialize(&mut deserializer))?
                     };
+                    let metadata = ResponseMetadata::new(
+                        &response,
+                        &self.timings,
+                        self.request_body_size,
+                        body.len(),
+                        self.reused,
+                    );
                     rpc_response.set_body(body);
-                    return Ok(Async::Ready((rpc_response, response.finish())));
+                    let connection = if is_keep_alive(&response) {
+                        Some(response.finish())
+                    } else {
+                        None
+                    };
+                    return Ok(Async::Ready((rpc_response, metadata, connection)));
                 }
                 _ => unreachable!(),
             };
@@ -129,3 +578,229 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use fibers::sync::oneshot::MonitorError;
+
+    use pool::RpcClientPool;
+    use test_util::*;
+    use super::*;
+
+    #[test]
+    fn keep_alive_works() {
+        let mut builder = server_builder();
+        builder.set_keep_alive_timeout(Duration::from_millis(200));
+        builder
+            .register_with_context(PeerPortHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut client = RpcClient::new(addr);
+        let mut call = |client: &mut RpcClient| {
+            let request = hello("world");
+            let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::Ok { body } => String::from_utf8(body).unwrap(),
+                r => panic!("Unexpected response: {:?}", r),
+            }
+        };
+
+        // The connection is reused.
+        let port0 = call(&mut client);
+        let port1 = call(&mut client);
+        assert_eq!(port0, port1);
+
+        // The server closes the idle connection, then the client reconnects transparently.
+        thread::sleep(Duration::from_millis(500));
+        let port2 = call(&mut client);
+        assert_ne!(port1, port2);
+
+        // Connections are not reused if keep-alive is disabled.
+        client.set_max_idle_connections(0);
+        let port3 = call(&mut client);
+        let port4 = call(&mut client);
+        assert_ne!(port3, port4);
+    }
+
+    #[test]
+    fn timeouts_work() {
+        let mut builder = server_builder();
+        builder.register(SlowHelloHandler, Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let request = || hello("slow");
+
+        // Client default
+        let mut client = RpcClient::new(addr);
+        client.set_timeout(Duration::from_millis(100));
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request()));
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Per call
+        let mut client = RpcClient::new(addr);
+        let mut options = CallOptions::new();
+        options.set_read_timeout(Duration::from_millis(100));
+        let call = client.call_with_options::<Hello>(request(), options);
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Pool
+        let mut pool = RpcClientPool::new();
+        pool.set_read_timeout(Duration::from_millis(100));
+        let handle = pool.handle();
+        executor.spawn(pool);
+        let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(request()));
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn stale_connection_works() {
+        // A server which closes a kept-alive connection when the second request arrives.
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepts_clone = accepts.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepts_clone.fetch_add(1, Ordering::SeqCst);
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).unwrap();
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    stream.write_all(response).unwrap();
+                    let _ = stream.read(&mut buf);
+                });
+            }
+        });
+
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let request = || hello("world");
+
+        // Client
+        let mut client = RpcClient::new(addr);
+        for _ in 0..2 {
+            let monitor = executor.spawn_monitor(client.call::<Hello>(request()));
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::Ok { body } => assert_eq!(body, b"ok"),
+                r => panic!("Unexpected response: {:?}", r),
+            }
+        }
+        assert_eq!(accepts.swap(0, Ordering::SeqCst), 2);
+
+        // Pool
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+        for _ in 0..2 {
+            let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(request()));
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::Ok { body } => assert_eq!(body, b"ok"),
+                r => panic!("Unexpected response: {:?}", r),
+            }
+        }
+        assert_eq!(accepts.swap(0, Ordering::SeqCst), 2);
+
+        // Non idempotent procedures are not sent again by default.
+        let mut client = RpcClient::new(addr);
+        let monitor = executor.spawn_monitor(client.call::<PostHello>(request()));
+        assert!(executor.run_future(monitor).unwrap().is_ok());
+        let monitor = executor.spawn_monitor(client.call::<PostHello>(request()));
+        assert!(executor.run_future(monitor).unwrap().is_err());
+        assert_eq!(accepts.swap(0, Ordering::SeqCst), 1);
+
+        let mut client = RpcClient::new(addr);
+        client.set_resend_non_idempotent(true);
+        for _ in 0..2 {
+            let monitor = executor.spawn_monitor(client.call::<PostHello>(request()));
+            assert!(executor.run_future(monitor).unwrap().is_ok());
+        }
+        assert_eq!(accepts.swap(0, Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn broken_response_is_not_resent() {
+        // A server which responds with a broken response head to the second request.
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepts_clone = accepts.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepts_clone.fetch_add(1, Ordering::SeqCst);
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).unwrap();
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    stream.write_all(response).unwrap();
+                    let _ = stream.read(&mut buf).unwrap();
+                    stream.write_all(b"broken\r\n\r\n").unwrap();
+                });
+            }
+        });
+
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut client = RpcClient::new(addr);
+        let monitor = executor.spawn_monitor(client.call::<Hello>(hello("world")));
+        assert!(executor.run_future(monitor).unwrap().is_ok());
+        let monitor = executor.spawn_monitor(client.call::<Hello>(hello("world")));
+        assert!(executor.run_future(monitor).unwrap().is_err());
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn metadata_works() {
+        let mut builder = server_builder();
+        builder.register(NamedHelloHandler("meta"), Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut client = RpcClient::new(addr);
+        for reused in &[false, true] {
+            let request = hello("world");
+            let call = client.call::<Hello>(request).with_metadata();
+            let monitor = executor.spawn_monitor(call);
+            let (response, metadata) = executor.run_future(monitor).unwrap().unwrap();
+            match response {
+                HelloResponse::Ok { body } => assert_eq!(body, b"meta"),
+                r => panic!("Unexpected response: {:?}", r),
+            }
+            assert_eq!(metadata.status(), 200);
+            assert_eq!(metadata.reason(), "OK");
+            assert_eq!(metadata.header("content-length"), Some(&b"4"[..]));
+            assert_eq!(metadata.request_body_size(), 0);
+            assert_eq!(metadata.response_body_size(), 4);
+            assert_eq!(metadata.reused_connection(), *reused);
+            assert!(metadata.connect_time() <= metadata.time_to_first_byte());
+            assert!(metadata.time_to_first_byte() <= metadata.total_time());
+        }
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+        let request = hello("world");
+        let call = handle.client(addr).call::<Hello>(request).with_metadata();
+        let monitor = executor.spawn_monitor(call);
+        let (_, metadata) = executor.run_future(monitor).unwrap().unwrap();
+        assert_eq!(metadata.status(), 200);
+        assert!(!metadata.reused_connection());
+    }
+}
@@ -0,0 +1,153 @@
+use fibers::net::TcpStream;
+use miasht::server::Request;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use url::Url;
+
+use types::{EntryPoint, HttpMethod};
+
+/// The context of an RPC request handled by a server.
+///
+/// This is passed to middlewares and `HandleRpcWithContext` handlers.
+#[derive(Debug)]
+pub struct RequestContext {
+    peer_addr: SocketAddr,
+    url: Url,
+    method: HttpMethod,
+    entry_point: EntryPoint,
+    headers: Vec<(String, Vec<u8>)>,
+    extensions: Extensions,
+}
+impl RequestContext {
+    pub(crate) fn new(
+        peer_addr: SocketAddr,
+        entry_point: EntryPoint,
+        url: &Url,
+        request: &Request<TcpStream>,
+    ) -> Self {
+        let headers = request
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        RequestContext {
+            peer_addr,
+            url: url.clone(),
+            method: request.method(),
+            entry_point,
+            headers,
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Returns the address of the client which issued the request.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the parsed URL of the request.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Returns the HTTP method of the request.
+    pub fn method(&self) -> HttpMethod {
+        self.method
+    }
+
+    /// Returns the entry point of the procedure which handles the request.
+    pub fn entry_point(&self) -> &EntryPoint {
+        &self.entry_point
+    }
+
+    /// Returns the value of the first header which has the specified name.
+    ///
+    /// The name is compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers()
+            .find(|&(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Returns an iterator over the headers of the request.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    /// Returns a reference to the extensions of the request.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns a mutable reference to the extensions of the request.
+    ///
+    /// Middlewares can use this to pass arbitrary values to subsequent middlewares and handlers.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+}
+
+/// A type map which holds per-request values.
+///
+/// # Examples
+///
+/// ```
+/// use htrpc::Extensions;
+///
+/// struct UserId(u64);
+///
+/// let mut extensions = Extensions::new();
+/// assert!(extensions.get::<UserId>().is_none());
+///
+/// extensions.insert(UserId(10));
+/// assert_eq!(extensions.get::<UserId>().map(|x| x.0), Some(10));
+/// ```
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+impl Extensions {
+    /// Makes a new empty `Extensions` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a value.
+    ///
+    /// If a value of the same type has already been inserted, it will be returned.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    /// Returns a reference to the value of the type `T`.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Returns a mutable reference to the value of the type `T`.
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Removes the value of the type `T`.
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+}
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
@@ -266,7 +266,7 @@ impl<'de, 'a> de::MapAccess<'de> for &'a mut RpcRequestDeserializer<'de> {
         match self.phase {
             Phase::Init => unreachable!(),
             Phase::Path => {
-                let mut de = track!(UrlPathDeserializer::new(self.entry_point, self.url))?;
+                let mut de = track!(UrlPathDeserializer::new(self.entry_point.clone(), self.url))?;
                 let v = track!(seed.deserialize(&mut de))?;
                 Ok(v)
             }
@@ -1,3 +1,4 @@
+use serde::de::value::SeqDeserializer;
 use serde::de::{self, Visitor};
 use std;
 use std::iter::Peekable;
@@ -5,7 +6,7 @@ use std::str::Split;
 use trackable::error::ErrorKindExt;
 use url::Url;
 
-use types::EntryPoint;
+use types::{EntryPoint, PathSegment};
 use {Error, ErrorKind, Result};
 
 /// `Deserializer` implementation for URL path.
@@ -23,12 +24,13 @@ impl<'de> UrlPathDeserializer<'de> {
         let segments = track!(url
             .path_segments()
             .ok_or_else(|| ErrorKind::Invalid.error(),))?;
+        let free_vars = entry_point.var_count();
         Ok(UrlPathDeserializer {
             in_seq: false,
             segments: segments.peekable(),
             entry_point,
             index: 0,
-            free_vars: entry_point.var_count(),
+            free_vars,
         })
     }
     fn is_end_of_segment(&mut self) -> bool {
@@ -54,6 +56,12 @@ impl<'de> UrlPathDeserializer<'de> {
         );
         track_assert!(!self.is_end_of_segment(), ErrorKind::Invalid);
         let i = self.index;
+        track_assert_ne!(
+            self.entry_point.segments()[i],
+            PathSegment::Tail,
+            ErrorKind::Invalid,
+            "A tail segment must be a string or a sequence of strings"
+        );
         self.index += 1;
         if let Some(expected) = self.entry_point.segments()[i].as_option() {
             let s = self.segments.next().unwrap();
@@ -65,6 +73,29 @@ impl<'de> UrlPathDeserializer<'de> {
             Ok(s)
         }
     }
+    fn is_next_value_tail(&self) -> bool {
+        self.entry_point.segments()[self.index..]
+            .iter()
+            .find(|s| s.as_option().is_none())
+            == Some(&PathSegment::Tail)
+    }
+    fn next_tail_values(&mut self) -> Result<Vec<String>> {
+        while let Some(expected) = self.entry_point.segments()[self.index].as_option() {
+            let s = track!(self
+                .segments
+                .next()
+                .ok_or_else(|| ErrorKind::Invalid.error(),))?;
+            track_assert_eq!(s, expected, ErrorKind::Invalid);
+            self.index += 1;
+        }
+        self.index += 1;
+        self.free_vars -= 1;
+        let mut values = Vec::new();
+        for s in &mut self.segments {
+            values.push(track!(percent_decode(s))?);
+        }
+        Ok(values)
+    }
 }
 impl<'de, 'a> de::Deserializer<'de> for &'a mut UrlPathDeserializer<'de> {
     type Error = Error;
@@ -185,6 +216,10 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut UrlPathDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
+        if self.in_seq && self.is_next_value_tail() {
+            let values = track!(self.next_tail_values())?;
+            return track!(visitor.visit_string(values.join("/")));
+        }
         let v = track!(self.next_value())?;
         let s = track!(percent_decode(v))?;
         track!(visitor.visit_string(s))
@@ -243,6 +278,10 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut UrlPathDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
+        if self.in_seq && self.is_next_value_tail() {
+            let values = track!(self.next_tail_values())?;
+            return track!(visitor.visit_seq(SeqDeserializer::new(values.into_iter())));
+        }
         track_assert!(!self.in_seq, ErrorKind::Invalid);
         self.in_seq = true;
         track!(visitor.visit_seq(self))
@@ -380,4 +419,33 @@ mod test {
         assert_eq!(v0, "hello world");
         assert_eq!(v1, 3);
     }
+
+    #[test]
+    fn tail_works() {
+        let entry_point = htrpc_entry_point!["objects", _, *];
+
+        #[derive(Deserialize)]
+        struct Joined(String, String);
+
+        #[derive(Deserialize)]
+        struct Segments(String, Vec<String>);
+
+        let url = Url::parse("http://localhost/objects/foo/a/b%20c/d").unwrap();
+        let mut deserializer =
+            track_try_unwrap!(UrlPathDeserializer::new(entry_point.clone(), &url));
+        let Joined(v0, v1) = track_try_unwrap!(Joined::deserialize(&mut deserializer));
+        assert_eq!(v0, "foo");
+        assert_eq!(v1, "a/b c/d");
+
+        let mut deserializer =
+            track_try_unwrap!(UrlPathDeserializer::new(entry_point.clone(), &url));
+        let Segments(v0, v1) = track_try_unwrap!(Segments::deserialize(&mut deserializer));
+        assert_eq!(v0, "foo");
+        assert_eq!(v1, ["a", "b c", "d"]);
+
+        let url = Url::parse("http://localhost/objects/foo").unwrap();
+        let mut deserializer = track_try_unwrap!(UrlPathDeserializer::new(entry_point, &url));
+        let Segments(_, v1) = track_try_unwrap!(Segments::deserialize(&mut deserializer));
+        assert!(v1.is_empty());
+    }
 }
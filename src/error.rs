@@ -77,7 +77,7 @@ impl<E: Into<Error>> From<Option<E>> for Error {
         if let Some(e) = f {
             e.into()
         } else {
-            ErrorKind::Other.cause("timeout").into()
+            ErrorKind::Timeout.cause("timeout").into()
         }
     }
 }
@@ -122,6 +122,12 @@ pub enum ErrorKind {
     /// Input data is invalid.
     Invalid,
 
+    /// An operation timed out.
+    Timeout,
+
+    /// The circuit breaker of the destination is open.
+    CircuitOpen,
+
     /// Other error.
     Other,
 }
@@ -6,6 +6,7 @@
 extern crate fibers;
 extern crate futures;
 extern crate handy_async;
+extern crate httparse;
 extern crate miasht;
 extern crate serde;
 #[macro_use]
@@ -19,43 +20,55 @@ extern crate url;
 
 pub use miasht::builtin::futures::FutureExt;
 
-#[allow(missing_docs)]
-pub type BodyReader =
-    miasht::builtin::io::BodyReader<miasht::server::Request<fibers::net::TcpStream>>;
-
 #[allow(missing_docs)]
 pub fn content_length(body: &BodyReader) -> Option<u64> {
-    if let miasht::builtin::io::BodyReader::FixedLength(ref r) = *body {
-        Some(r.limit())
-    } else {
-        None
-    }
+    body.content_length()
 }
 
 #[allow(missing_docs)]
 pub type ReadBody<T> =
     Box<dyn futures::Future<Item = (BodyReader, T), Error = Error> + Send + 'static>;
 
-pub use client::RpcClient;
+pub use body::BodyReader;
+pub use client::{CallOptions, RpcClient};
+pub use context::{Extensions, RequestContext};
 pub use error::{Error, ErrorKind};
-pub use procedure::{HandleRpc, Procedure, RpcRequest, RpcResponse};
-pub use server::{RpcServer, RpcServerBuilder};
+pub use metadata::ResponseMetadata;
+pub use procedure::{
+    HandleFallibleRpc, HandleRpc, HandleRpcWithContext, IntoErrorResponse, Procedure, RpcRequest,
+    RpcResponse,
+};
+pub use retry::{RetryCall, RetryPolicy};
+pub use server::{ProcedureOptions, RpcServer, RpcServerBuilder, RpcServerHandle};
 
 /// A helper macro to construct an `EntryPoint` instance.
 ///
+/// `_` represents a variable segment and `*` represents a tail segment
+/// which matches zero or more remaining segments (it must be the last one).
+///
 /// # Examples
 ///
 /// ```
 /// # #[macro_use]
 /// # extern crate htrpc;
+/// use std::borrow::Cow;
 /// use htrpc::types::{EntryPoint, PathSegment};
 ///
 /// # fn main() {
-/// static SEGMENTS: &[PathSegment] =
-///     &[PathSegment::Val("foo"), PathSegment::Var, PathSegment::Val("baz")];
+/// static SEGMENTS: &[PathSegment] = &[
+///     PathSegment::Val(Cow::Borrowed("foo")),
+///     PathSegment::Var,
+///     PathSegment::Val(Cow::Borrowed("baz")),
+/// ];
 /// let p0 = EntryPoint::new(SEGMENTS);
 /// let p1 = htrpc_entry_point!["foo", _, "baz"];
 /// assert_eq!(p0, p1);
+///
+/// static TAIL_SEGMENTS: &[PathSegment] =
+///     &[PathSegment::Val(Cow::Borrowed("objects")), PathSegment::Tail];
+/// let p2 = EntryPoint::new(TAIL_SEGMENTS);
+/// let p3 = htrpc_entry_point!["objects", *];
+/// assert_eq!(p2, p3);
 /// # }
 /// ```
 #[macro_export]
@@ -75,26 +88,38 @@ macro_rules! htrpc_expand_segment {
     (_) => {
         $crate::types::PathSegment::Var
     };
+    (*) => {
+        $crate::types::PathSegment::Tail
+    };
     ($s:expr) => {
-        $crate::types::PathSegment::Val($s)
+        $crate::types::PathSegment::Val(::std::borrow::Cow::Borrowed($s))
     };
 }
 
 pub mod deserializers;
 pub mod json;
 pub mod json_pretty;
+pub mod middleware;
 pub mod msgpack;
 pub mod pool;
 pub mod rfc7807;
 pub mod serializers;
 pub mod types;
 
+mod balancer;
+mod body;
+mod circuit_breaker;
 mod client;
+mod context;
 mod error;
+mod metadata;
 mod misc;
 mod procedure;
+mod retry;
 mod router;
 mod server;
+#[cfg(test)]
+mod test_util;
 
 /// This crate specific `Result` type.
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+use fibers::net::TcpStream;
+use miasht::client::Response;
+
+/// The metadata of an HTTP response received by a client.
+///
+/// This can be obtained by the `with_metadata` method of the `Call` futures
+/// (e.g., `RpcClient::call(..).with_metadata()`).
+#[derive(Debug, Clone)]
+pub struct ResponseMetadata {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, Vec<u8>)>,
+    request_body_size: usize,
+    response_body_size: usize,
+    reused_connection: bool,
+    connect_time: Duration,
+    time_to_first_byte: Duration,
+    total_time: Duration,
+}
+impl ResponseMetadata {
+    pub(crate) fn new(
+        response: &Response<TcpStream>,
+        timings: &Timings,
+        request_body_size: usize,
+        response_body_size: usize,
+        reused_connection: bool,
+    ) -> Self {
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let connected_at = timings.connected_at.unwrap_or(timings.started_at);
+        let first_byte_at = timings.first_byte_at.unwrap_or(connected_at);
+        ResponseMetadata {
+            status: response.status().code(),
+            reason: response.status().reason().to_owned(),
+            headers,
+            request_body_size,
+            response_body_size,
+            reused_connection,
+            connect_time: connected_at - timings.started_at,
+            time_to_first_byte: first_byte_at - timings.started_at,
+            total_time: timings.started_at.elapsed(),
+        }
+    }
+
+    /// Returns the HTTP status code of the response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the reason phrase of the response.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Returns the value of the first header which has the specified name.
+    ///
+    /// The name is compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers()
+            .find(|&(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Returns an iterator over the headers of the response.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    /// Returns the number of bytes of the request body.
+    pub fn request_body_size(&self) -> usize {
+        self.request_body_size
+    }
+
+    /// Returns the number of bytes of the response body.
+    pub fn response_body_size(&self) -> usize {
+        self.response_body_size
+    }
+
+    /// Returns `true` if the request was sent over a reused (keep-alive) connection.
+    pub fn reused_connection(&self) -> bool {
+        self.reused_connection
+    }
+
+    /// Returns the time taken to acquire the connection.
+    ///
+    /// This is almost zero if an idle connection is reused.
+    pub fn connect_time(&self) -> Duration {
+        self.connect_time
+    }
+
+    /// Returns the time taken until the response head is received.
+    pub fn time_to_first_byte(&self) -> Duration {
+        self.time_to_first_byte
+    }
+
+    /// Returns the time taken until the whole response is received.
+    pub fn total_time(&self) -> Duration {
+        self.total_time
+    }
+}
+
+/// The points in time at which the phases of a call have been completed.
+#[derive(Debug)]
+pub(crate) struct Timings {
+    pub started_at: Instant,
+    pub connected_at: Option<Instant>,
+    pub first_byte_at: Option<Instant>,
+}
+impl Timings {
+    pub fn new() -> Self {
+        Timings {
+            started_at: Instant::now(),
+            connected_at: None,
+            first_byte_at: None,
+        }
+    }
+}
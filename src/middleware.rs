@@ -0,0 +1,193 @@
+//! Server side middlewares.
+//!
+//! A middleware intercepts the RPC requests and responses of procedures registered to
+//! `RpcServerBuilder`. It can be registered globally (`RpcServerBuilder::add_middleware`)
+//! or for a specific procedure (`ProcedureOptions::add_middleware`).
+//!
+//! For each request, the global middlewares are invoked first (in registration order),
+//! followed by the procedure specific ones.
+//! The `after_handle` methods are invoked in the reverse order.
+//!
+//! Middlewares are applied only to the requests routed to a procedure.
+//! The responses generated by the server itself bypass them, i.e.,
+//! `404 Not Found`, `405 Method Not Allowed`, the responses to `OPTIONS` requests,
+//! `414 URI Too Long`, `431 Request Header Fields Too Large`
+//! and `503 Service Unavailable` due to the in-flight request limit.
+//! Also, if the request body of a procedure is too large (`413`)
+//! or is not received in time (`408`), the connection is closed without invoking `after_handle`.
+use miasht::header::HeadersMut;
+use serdeconv;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use rfc7807::Problem;
+use types::HttpStatus;
+use {RequestContext, RpcResponse};
+
+/// This trait allows to intercept RPC requests and responses on the server side.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called before the RPC handler of the request is invoked.
+    ///
+    /// If this method returns `Some(response)`, the request is short-circuited:
+    /// the remaining middlewares and the handler are skipped,
+    /// and `response` is sent to the client.
+    ///
+    /// The extensions of `context` can be used to pass values to subsequent middlewares
+    /// and handlers.
+    ///
+    /// The default implementation returns `None`.
+    fn before_handle(&self, context: &mut RequestContext) -> Option<RawResponse> {
+        let _ = context;
+        None
+    }
+
+    /// Called before the response of the request is sent to the client.
+    ///
+    /// This method is invoked for every middleware of which `before_handle` has been called,
+    /// even if the request is short-circuited.
+    ///
+    /// The default implementation does nothing.
+    fn after_handle(&self, context: &RequestContext, response: &mut ResponseHead) {
+        let _ = (context, response);
+    }
+}
+
+/// The head part of an outgoing RPC response passed to middlewares.
+pub struct ResponseHead<'a> {
+    status: u16,
+    body: &'a [u8],
+    headers: HeadersMut<'a>,
+}
+impl<'a> ResponseHead<'a> {
+    /// Returns the status code of the response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the body of the response.
+    pub fn body(&self) -> &[u8] {
+        self.body
+    }
+
+    /// Adds a header to the response.
+    pub fn add_header(&mut self, name: &str, value: &[u8]) {
+        self.headers.add_raw_header(name, value);
+    }
+}
+impl<'a> fmt::Debug for ResponseHead<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseHead")
+            .field("status", &self.status)
+            .field("body_len", &self.body.len())
+            .finish()
+    }
+}
+
+/// An RPC response used by middlewares to short-circuit requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawResponse {
+    status: u16,
+    header: BTreeMap<String, String>,
+    #[serde(skip)]
+    body: Vec<u8>,
+}
+impl RawResponse {
+    /// Makes a new `RawResponse` instance which has no headers and an empty body.
+    pub fn new(status: HttpStatus) -> Self {
+        RawResponse {
+            status: status.code(),
+            header: BTreeMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Makes a new `RawResponse` instance which represents `problem`.
+    pub fn from_problem(problem: &Problem) -> Self {
+        let mut response = RawResponse {
+            status: problem.get_status_code(),
+            header: BTreeMap::new(),
+            body: Vec::new(),
+        };
+        response.add_header("content-type", "application/problem+json");
+        response.body = serdeconv::to_json_string_pretty(problem)
+            .expect("Never fails")
+            .into_bytes();
+        response
+    }
+
+    /// Adds a header to this response.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.header.insert(name.to_lowercase(), value.to_owned());
+    }
+
+    /// Sets the body of this response.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+}
+impl RpcResponse for RawResponse {
+    fn body(&mut self) -> Box<dyn AsRef<[u8]> + Send + 'static> {
+        Box::new(::std::mem::take(&mut self.body))
+    }
+    fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+}
+
+/// The middlewares applied to a request.
+///
+/// The default value is an empty chain,
+/// which is used for the responses generated by the server itself.
+#[derive(Default)]
+pub(crate) struct MiddlewareChain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    context: Option<RequestContext>,
+}
+impl MiddlewareChain {
+    pub fn new(
+        global: &[Arc<dyn Middleware>],
+        local: &[Arc<dyn Middleware>],
+        context: RequestContext,
+    ) -> Self {
+        let middlewares = global.iter().chain(local.iter()).cloned().collect();
+        MiddlewareChain {
+            middlewares,
+            context: Some(context),
+        }
+    }
+
+    /// Returns the context of the request.
+    pub fn context(&self) -> Option<&RequestContext> {
+        self.context.as_ref()
+    }
+
+    /// Invokes `before_handle` of the middlewares.
+    ///
+    /// If a middleware short-circuits the request,
+    /// the subsequent middlewares are removed from this chain.
+    pub fn before_handle(&mut self) -> Option<RawResponse> {
+        let context = self.context.as_mut()?;
+        for (i, middleware) in self.middlewares.iter().enumerate() {
+            if let Some(response) = middleware.before_handle(context) {
+                self.middlewares.truncate(i + 1);
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    /// Invokes `after_handle` of the middlewares in the reverse order.
+    pub fn after_handle(&self, status: u16, body: &[u8], headers: HeadersMut) {
+        if let Some(ref context) = self.context {
+            let mut response = ResponseHead {
+                status,
+                body,
+                headers,
+            };
+            for middleware in self.middlewares.iter().rev() {
+                middleware.after_handle(context, &mut response);
+            }
+        }
+    }
+}
@@ -1,23 +1,34 @@
 //! Connection pool.
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, Bound, HashMap};
+use std::collections::{BTreeMap, Bound, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
-use std::time::{Duration, SystemTime};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use fibers::net::TcpStream;
 use fibers::sync::mpsc;
 use fibers::sync::oneshot;
-use fibers::time::timer::{TimeoutAfter, TimerExt};
+use fibers::time::timer::{self, TimeoutAfter, TimerExt};
 use futures::{self, Async, Future, Poll, Stream};
 use futures::future::Done;
 use handy_async::future::Phase;
 use miasht;
+use slog::{Discard, Logger};
 use trackable::error::ErrorKindExt;
 
-use {Error, ErrorKind, Procedure};
-use client::CallInner;
+use {CallOptions, Error, ErrorKind, Procedure, ResponseMetadata, Result, RetryCall, RetryPolicy};
+use circuit_breaker::CircuitBreaker;
+use client::{self, CallInner, Reconnect};
+use retry::Caller;
+use types::EntryPoint;
+
+pub use balancer::{BalanceStrategy, BalancedCall, BalancedRpcClient, HedgedCall, HedgingPolicy};
+pub use circuit_breaker::{CircuitBreakerPolicy, CircuitState};
 
 type TcpConnection = miasht::client::Connection<TcpStream>;
+type LeaseSlot = Arc<Mutex<Option<Lease>>>;
+pub(crate) type SharedBlacklist = Arc<Mutex<HashMap<SocketAddr, SystemTime>>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ConnectionId {
@@ -43,45 +54,132 @@ impl Ord for ConnectionId {
 
 #[derive(Debug)]
 enum Command {
+    /// If `reuse` is `false`, a new connection is established in place of
+    /// the dead connection of the caller (i.e., no new lease is issued).
     AcquireConnection {
         addr: SocketAddr,
+        reuse: bool,
         reply: oneshot::Sender<PooledConnection>,
     },
     ReleaseConnection {
         addr: SocketAddr,
         connection: TcpConnection,
+        outcome: Option<Outcome>,
+    },
+    CloseConnection {
+        addr: SocketAddr,
+        outcome: Option<Outcome>,
+    },
+    Connected {
+        addr: SocketAddr,
+    },
+    ConnectFailed {
+        addr: SocketAddr,
+        timed_out: bool,
+    },
+    GetStats {
+        reply: oneshot::Sender<PoolStats>,
     },
     AddToBlacklist {
         addr: SocketAddr,
+        duration: Duration,
+    },
+    RemoveFromBlacklist {
+        addr: SocketAddr,
     },
+    ClearBlacklist,
+}
+
+/// The result of a call.
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    Response { status: u16, elapsed: Duration },
+    Failure,
+}
+
+/// A lease of one of the connections to an address.
+///
+/// If a lease is dropped without releasing the connection,
+/// the pool regards the connection as closed.
+///
+/// The outcome of the call (if any) is also reported to the pool.
+/// `None` means that the call has been canceled.
+#[derive(Debug)]
+struct Lease {
+    addr: SocketAddr,
+    command_tx: mpsc::Sender<Command>,
+    released: bool,
+    acquired_at: Instant,
+    outcome: Option<Outcome>,
+}
+impl Lease {
+    fn set_response(&mut self, status: u16) {
+        let elapsed = self.acquired_at.elapsed();
+        self.outcome = Some(Outcome::Response { status, elapsed });
+    }
+    fn set_failure(&mut self) {
+        self.outcome = Some(Outcome::Failure);
+    }
+    fn release(mut self, connection: TcpConnection) {
+        self.released = true;
+        let addr = self.addr;
+        let outcome = self.outcome;
+        let _ = self.command_tx.send(Command::ReleaseConnection {
+            addr,
+            connection,
+            outcome,
+        });
+    }
+}
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.released {
+            let addr = self.addr;
+            let outcome = self.outcome;
+            let _ = self
+                .command_tx
+                .send(Command::CloseConnection { addr, outcome });
+        }
+    }
 }
 
 struct PooledConnection {
-    on_error: Option<(SocketAddr, mpsc::Sender<Command>)>,
+    notify: Option<(SocketAddr, mpsc::Sender<Command>)>,
+    lease: Option<Lease>,
     phase: Phase<Done<TcpConnection, Error>, TimeoutAfter<miasht::client::Connect>>,
 }
 impl PooledConnection {
     fn failed(error: Error) -> Self {
         let phase = Phase::A(futures::failed(error));
         PooledConnection {
-            on_error: None,
+            notify: None,
+            lease: None,
             phase,
         }
     }
 }
 impl Future for PooledConnection {
-    type Item = TcpConnection;
+    type Item = (TcpConnection, bool);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match track!(self.phase.poll().map_err(Error::from)) {
+        match self.phase.poll() {
             Err(e) => {
-                if let Some((addr, tx)) = self.on_error.take() {
-                    let _ = tx.send(Command::AddToBlacklist { addr });
+                if let Some(ref mut lease) = self.lease {
+                    lease.set_failure();
                 }
-                Err(e)
+                if let Some((addr, tx)) = self.notify.take() {
+                    let timed_out = matches!(e, Phase::B(None));
+                    let _ = tx.send(Command::ConnectFailed { addr, timed_out });
+                }
+                Err(track!(Error::from(e)))
+            }
+            Ok(Async::Ready(Phase::A(connection))) => Ok(Async::Ready((connection, true))),
+            Ok(Async::Ready(Phase::B(connection))) => {
+                if let Some((addr, tx)) = self.notify.take() {
+                    let _ = tx.send(Command::Connected { addr });
+                }
+                Ok(Async::Ready((connection, false)))
             }
-            Ok(Async::Ready(Phase::A(connection))) => Ok(Async::Ready(connection)),
-            Ok(Async::Ready(Phase::B(connection))) => Ok(Async::Ready(connection)),
             Ok(Async::Ready(_)) => unreachable!(),
             Ok(Async::NotReady) => Ok(Async::NotReady),
         }
@@ -93,18 +191,107 @@ impl fmt::Debug for PooledConnection {
     }
 }
 
+/// A snapshot of the statistics of an `RpcClientPool`.
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    /// The number of idle connections for each address.
+    pub idle_connections: HashMap<SocketAddr, usize>,
+
+    /// The total number of connections established by the pool.
+    pub created_connections: u64,
+
+    /// The total number of times idle connections were reused.
+    pub reused_connections: u64,
+
+    /// The total number of failed connection attempts (including timeouts).
+    pub connect_failures: u64,
+
+    /// The total number of timed out connection attempts.
+    pub connect_timeouts: u64,
+
+    /// The blacklisted addresses and the times until which they are suspended.
+    pub blacklist: HashMap<SocketAddr, SystemTime>,
+
+    /// The states of the circuit breakers which are not closed.
+    pub circuits: HashMap<SocketAddr, CircuitState>,
+}
+
+/// A `Future` which results in the blacklisted addresses of an `RpcClientPool`
+/// and the times until which they are suspended.
+#[derive(Debug)]
+pub struct GetBlacklist {
+    stats: GetStats,
+}
+impl Future for GetBlacklist {
+    type Item = HashMap<SocketAddr, SystemTime>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track!(self.stats.poll())?.map(|stats| stats.blacklist))
+    }
+}
+
+/// A `Future` which results in a snapshot of the statistics of an `RpcClientPool`.
+#[derive(Debug)]
+pub struct GetStats {
+    reply_rx: oneshot::Receiver<PoolStats>,
+}
+impl Future for GetStats {
+    type Item = PoolStats;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.reply_rx.poll().map_err(Error::from))
+    }
+}
+
+#[derive(Debug)]
+struct IdleConnection {
+    connection: TcpConnection,
+    released_at: Instant,
+}
+
 /// This managements a pool of RPC clients.
+///
+/// Before reusing a pooled connection, the pool checks whether the server has closed it.
+/// If a reused connection turns out to be dead before the response arrives,
+/// the request is sent again once over a new connection.
+///
+/// An address is blacklisted (i.e., calls to it fail immediately) for a while
+/// if connecting to it fails. The suspended duration is doubled for each consecutive failure
+/// (see `set_suspended_duration` and `set_max_suspended_duration`), and reset on success.
+///
+/// If the number of open connections to an address reaches the limit
+/// (see `set_max_connections_per_addr`), calls to the address wait
+/// for a connection in the order in which they were issued.
 #[derive(Debug)]
 pub struct RpcClientPool {
     pool_size: usize,
-    connections: BTreeMap<ConnectionId, TcpConnection>,
+    connections: BTreeMap<ConnectionId, IdleConnection>,
     lru_queue: BTreeMap<u64, SocketAddr>,
+    open_connections: HashMap<SocketAddr, usize>,
+    waiters: HashMap<SocketAddr, VecDeque<oneshot::Sender<PooledConnection>>>,
+    max_idle_connections_per_addr: usize,
+    max_connections_per_addr: usize,
     command_tx: mpsc::Sender<Command>,
     command_rx: mpsc::Receiver<Command>,
     seq_no: u64,
     blacklist: HashMap<SocketAddr, SystemTime>,
+    shared_blacklist: SharedBlacklist,
+    recovering: HashSet<SocketAddr>,
+    consecutive_failures: HashMap<SocketAddr, u32>,
+    consecutive_server_errors: HashMap<SocketAddr, u32>,
+    server_error_threshold: Option<u32>,
+    circuit_breaker_policy: Option<CircuitBreakerPolicy>,
+    circuit_breakers: HashMap<SocketAddr, CircuitBreaker>,
+    stats: PoolStats,
+    logger: Logger,
     suspended_duration: Duration,
+    max_suspended_duration: Duration,
     connect_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    sweep_timer: Option<timer::Timeout>,
+    options: CallOptions,
+    retry_policy: RetryPolicy,
+    base_path: Option<EntryPoint>,
 }
 impl RpcClientPool {
     /// Makes a new `RpcClientPool` with the default pool size (1024).
@@ -119,65 +306,460 @@ impl RpcClientPool {
             pool_size,
             connections: BTreeMap::new(),
             lru_queue: BTreeMap::new(),
+            open_connections: HashMap::new(),
+            waiters: HashMap::new(),
+            max_idle_connections_per_addr: usize::MAX,
+            max_connections_per_addr: usize::MAX,
             command_tx,
             command_rx,
             seq_no: 0,
             blacklist: HashMap::new(),
+            shared_blacklist: SharedBlacklist::default(),
+            recovering: HashSet::new(),
+            consecutive_failures: HashMap::new(),
+            consecutive_server_errors: HashMap::new(),
+            server_error_threshold: None,
+            circuit_breaker_policy: None,
+            circuit_breakers: HashMap::new(),
+            stats: PoolStats::default(),
+            logger: Logger::root(Discard, o!()),
             suspended_duration: Duration::from_secs(60),
+            max_suspended_duration: Duration::from_secs(600),
             connect_timeout: Duration::from_secs(1),
+            idle_timeout: None,
+            sweep_timer: None,
+            options: CallOptions::new(),
+            retry_policy: RetryPolicy::new(),
+            base_path: None,
         }
     }
 
     /// Returns a handle of this pool.
+    ///
+    /// The default call options (e.g., `set_timeout`), the retry policy and the base path
+    /// are copied to the handle, so they should be set before calling this method.
     pub fn handle(&self) -> RpcClientPoolHandle {
         RpcClientPoolHandle {
             command_tx: self.command_tx.clone(),
+            blacklist: self.shared_blacklist.clone(),
+            options: self.options.clone(),
+            retry_policy: self.retry_policy.clone(),
+            base_path: self.base_path.clone(),
         }
     }
 
+    /// Enables the circuit breakers (one for each address) with the given policy.
+    ///
+    /// By default, the circuit breakers are disabled.
+    pub fn set_circuit_breaker_policy(&mut self, policy: CircuitBreakerPolicy) {
+        self.circuit_breaker_policy = Some(policy);
+    }
+
+    /// Sets the logger to this pool.
+    ///
+    /// The pool logs the events such as blacklisting an address and its recovery.
+    pub fn set_logger(&mut self, logger: Logger) {
+        self.logger = logger;
+    }
+
     /// Sets the suspended duration of an erroneous TCP address.
+    ///
+    /// This is the duration for the first failure,
+    /// and it is doubled for each consecutive failure.
+    ///
+    /// The default value is `60s`.
     pub fn set_suspended_duration(&mut self, duration: Duration) {
         self.suspended_duration = duration;
     }
 
+    /// Sets the upper limit of the suspended duration of an erroneous TCP address.
+    ///
+    /// The default value is `600s`.
+    pub fn set_max_suspended_duration(&mut self, duration: Duration) {
+        self.max_suspended_duration = duration;
+    }
+
+    /// Makes the pool blacklist an address if the server returns
+    /// `threshold` consecutive responses with 5xx status codes.
+    ///
+    /// By default, the responses do not affect the blacklist.
+    pub fn set_server_error_threshold(&mut self, threshold: u32) {
+        self.server_error_threshold = Some(threshold);
+    }
+
     /// Sets the timeout of a TCP connecting phase.
+    ///
+    /// If the timeout expires, the call will fail with `ErrorKind::Timeout`.
     pub fn set_connect_timeout(&mut self, timeout: Duration) {
         self.connect_timeout = timeout;
     }
 
+    /// Sets the maximum number of idle connections kept for each address.
+    ///
+    /// If the limit is exceeded, the least recently used connection to the address is closed.
+    ///
+    /// By default, there is no limit (but the total number is limited by the pool size).
+    pub fn set_max_idle_connections_per_addr(&mut self, count: usize) {
+        self.max_idle_connections_per_addr = count;
+    }
+
+    /// Sets the maximum number of open (i.e., idle or in-use) connections for each address.
+    ///
+    /// If the limit is reached, calls to the address wait until one of the connections is
+    /// released or closed. The waiting calls are served in FIFO order.
+    ///
+    /// By default, there is no limit.
+    pub fn set_max_connections_per_addr(&mut self, count: usize) {
+        self.max_connections_per_addr = count;
+    }
+
+    /// Sets the maximum duration for which a connection can stay idle in the pool.
+    ///
+    /// Connections idle longer than this are closed instead of being reused.
+    /// The pool also sweeps such connections periodically (every `timeout`).
+    ///
+    /// By default, idle connections are kept until the pool is full.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Sets the default total timeout of a call.
+    ///
+    /// See also `RpcClient::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.options.set_timeout(timeout);
+    }
+
+    /// Sets the default timeout for reading a response.
+    ///
+    /// See also `RpcClient::set_read_timeout`.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.options.set_read_timeout(timeout);
+    }
+
+    /// Sets the default timeout for writing a request.
+    ///
+    /// See also `RpcClient::set_write_timeout`.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.options.set_write_timeout(timeout);
+    }
+
+    /// Sets whether the requests of non idempotent procedures are sent again
+    /// if the reused connections have been closed by the server.
+    ///
+    /// See also `RpcClient::set_resend_non_idempotent`.
+    pub fn set_resend_non_idempotent(&mut self, enabled: bool) {
+        self.options.set_resend_non_idempotent(enabled);
+    }
+
+    /// Sets the retry policy used by `PooledRpcClient::call_with_retry`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Sets the default base path which is prepended to the entry points of procedures.
+    ///
+    /// See also `RpcClient::set_base_path` and `RpcClientPoolHandle::set_base_path`.
+    pub fn set_base_path(&mut self, base_path: EntryPoint) -> Result<()> {
+        track_assert!(
+            base_path.is_literal(),
+            ErrorKind::Invalid,
+            "A base path must consist of only literal segments: base_path={:?}",
+            base_path
+        );
+        self.base_path = Some(base_path);
+        Ok(())
+    }
+
     fn handle_command(&mut self, command: Command) {
         match command {
-            Command::AcquireConnection { addr, reply } => {
-                if let Some(suspended_until) = self.blacklist.remove(&addr) {
-                    if suspended_until > SystemTime::now() {
-                        self.blacklist.insert(addr, suspended_until);
-                        let future = PooledConnection::failed(
-                            ErrorKind::Other
-                                .cause(format!(
-                                    "The address {:?} is unavailable until {:?}",
-                                    addr, suspended_until
-                                ))
-                                .into(),
-                        );
-                        let _ = reply.send(future);
-                        return;
-                    }
+            Command::AcquireConnection { addr, reuse, reply } => {
+                if let Some(e) = self.check_blacklist(addr) {
+                    let _ = reply.send(PooledConnection::failed(e));
+                    return;
                 }
-                let future = self.acquire_connection(addr);
-                let _ = reply.send(future);
+                if !reuse {
+                    let _ = reply.send(self.connect(addr, None));
+                    return;
+                }
+                if !self.try_acquire_circuit(addr) {
+                    let e = ErrorKind::CircuitOpen
+                        .cause(format!("The circuit of the address {:?} is open", addr));
+                    let _ = reply.send(PooledConnection::failed(e.into()));
+                    return;
+                }
+                self.waiters
+                    .entry(addr)
+                    .or_default()
+                    .push_back(reply);
+                self.serve_waiters(addr);
             }
-            Command::ReleaseConnection { addr, connection } => {
+            Command::ReleaseConnection {
+                addr,
+                connection,
+                outcome,
+            } => {
                 self.release_connection(addr, connection);
+                self.handle_outcome(addr, outcome);
+                self.serve_waiters(addr);
+            }
+            Command::CloseConnection { addr, outcome } => {
+                self.decrement_open_connections(addr);
+                self.handle_outcome(addr, outcome);
+                self.serve_waiters(addr);
+            }
+            Command::Connected { addr } => {
+                self.stats.created_connections += 1;
+                self.consecutive_failures.remove(&addr);
+                if self.recovering.remove(&addr) {
+                    info!(self.logger, "Address recovered: {}", addr);
+                }
+            }
+            Command::ConnectFailed { addr, timed_out } => {
+                self.stats.connect_failures += 1;
+                if timed_out {
+                    self.stats.connect_timeouts += 1;
+                }
+                let reason = if timed_out {
+                    "connect timed out"
+                } else {
+                    "connect failed"
+                };
+                self.add_to_blacklist(addr, reason);
+                self.serve_waiters(addr);
+            }
+            Command::GetStats { reply } => {
+                let _ = reply.send(self.stats());
+            }
+            Command::AddToBlacklist { addr, duration } => {
+                let suspended_until = SystemTime::now() + duration;
+                self.blacklist.insert(addr, suspended_until);
+                self.publish_blacklist();
+                self.recovering.remove(&addr);
+                warn!(
+                    self.logger,
+                    "Address is blacklisted: addr={}, reason=manual, suspended_until={:?}",
+                    addr,
+                    suspended_until
+                );
+                self.serve_waiters(addr);
+            }
+            Command::RemoveFromBlacklist { addr } => {
+                self.remove_from_blacklist(addr);
+            }
+            Command::ClearBlacklist => {
+                let addrs = self.blacklist.keys().cloned().collect::<Vec<_>>();
+                for addr in addrs {
+                    self.remove_from_blacklist(addr);
+                }
+                self.consecutive_failures.clear();
+                self.consecutive_server_errors.clear();
             }
-            Command::AddToBlacklist { addr } => {
-                let suspended_until = SystemTime::now() + self.suspended_duration;
+        }
+    }
+    fn add_to_blacklist(&mut self, addr: SocketAddr, reason: &str) {
+        let failures = {
+            let failures = self.consecutive_failures.entry(addr).or_insert(0);
+            *failures += 1;
+            *failures
+        };
+        let max = self.max_suspended_duration;
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        let duration = self
+            .suspended_duration
+            .checked_mul(factor)
+            .map_or(max, |d| d.min(max));
+        let suspended_until = SystemTime::now() + duration;
+        self.blacklist.insert(addr, suspended_until);
+        self.publish_blacklist();
+        self.recovering.remove(&addr);
+        warn!(
+            self.logger,
+            "Address is blacklisted: addr={}, reason={}, consecutive_failures={}, \
+             suspended_until={:?}",
+            addr,
+            reason,
+            failures,
+            suspended_until
+        );
+    }
+    fn remove_from_blacklist(&mut self, addr: SocketAddr) {
+        self.consecutive_failures.remove(&addr);
+        self.consecutive_server_errors.remove(&addr);
+        self.recovering.remove(&addr);
+        if self.blacklist.remove(&addr).is_some() {
+            self.publish_blacklist();
+            info!(self.logger, "Address is removed from the blacklist: {}", addr);
+        }
+    }
+    fn publish_blacklist(&self) {
+        if let Ok(mut shared) = self.shared_blacklist.lock() {
+            shared.clone_from(&self.blacklist);
+        }
+    }
+    fn try_acquire_circuit(&mut self, addr: SocketAddr) -> bool {
+        let policy = if let Some(ref policy) = self.circuit_breaker_policy {
+            policy
+        } else {
+            return true;
+        };
+        let breaker = self.circuit_breakers
+            .entry(addr)
+            .or_insert_with(|| CircuitBreaker::new(policy.clone()));
+        let old_state = breaker.state();
+        let acquired = breaker.try_acquire();
+        if old_state != breaker.state() {
+            info!(
+                self.logger,
+                "Circuit state changed: addr={}, state={:?}",
+                addr,
+                breaker.state()
+            );
+        }
+        acquired
+    }
+    fn handle_outcome(&mut self, addr: SocketAddr, outcome: Option<Outcome>) {
+        let (status, elapsed) = match outcome {
+            Some(Outcome::Response { status, elapsed }) => (Some(status), elapsed),
+            Some(Outcome::Failure) => (None, Duration::from_secs(0)),
+            None => {
+                if let Some(breaker) = self.circuit_breakers.get_mut(&addr) {
+                    breaker.cancel();
+                }
+                return;
+            }
+        };
+        if let (Some(policy), Some(breaker)) = (
+            self.circuit_breaker_policy.as_ref(),
+            self.circuit_breakers.get_mut(&addr),
+        ) {
+            let old_state = breaker.state();
+            breaker.record(policy.is_success(status, elapsed));
+            if old_state != breaker.state() {
+                if breaker.state() == CircuitState::Open {
+                    warn!(self.logger, "Circuit is opened: addr={}", addr);
+                } else {
+                    info!(
+                        self.logger,
+                        "Circuit state changed: addr={}, state={:?}",
+                        addr,
+                        breaker.state()
+                    );
+                }
+            }
+        }
+        self.handle_status(addr, status);
+    }
+    fn handle_status(&mut self, addr: SocketAddr, status: Option<u16>) {
+        match status {
+            Some(status) if status >= 500 => {
+                let threshold = if let Some(threshold) = self.server_error_threshold {
+                    threshold
+                } else {
+                    return;
+                };
+                let errors = self.consecutive_server_errors.entry(addr).or_insert(0);
+                *errors += 1;
+                if *errors >= threshold {
+                    self.consecutive_server_errors.remove(&addr);
+                    self.add_to_blacklist(addr, "server errors");
+                }
+            }
+            Some(_) => {
+                self.consecutive_server_errors.remove(&addr);
+                self.consecutive_failures.remove(&addr);
+            }
+            None => {}
+        }
+    }
+    fn stats(&self) -> PoolStats {
+        let now = SystemTime::now();
+        let mut stats = self.stats.clone();
+        for id in self.connections.keys() {
+            *stats.idle_connections.entry(id.addr).or_insert(0) += 1;
+        }
+        stats.blacklist = self.blacklist
+            .iter()
+            .filter(|&(_, until)| *until > now)
+            .map(|(addr, until)| (*addr, *until))
+            .collect();
+        stats.circuits = self.circuit_breakers
+            .iter()
+            .filter(|&(_, breaker)| breaker.state() != CircuitState::Closed)
+            .map(|(addr, breaker)| (*addr, breaker.state()))
+            .collect();
+        stats
+    }
+    fn check_blacklist(&mut self, addr: SocketAddr) -> Option<Error> {
+        if let Some(suspended_until) = self.blacklist.remove(&addr) {
+            if suspended_until > SystemTime::now() {
                 self.blacklist.insert(addr, suspended_until);
+                let e = ErrorKind::Other.cause(format!(
+                    "The address {:?} is unavailable until {:?}",
+                    addr, suspended_until
+                ));
+                return Some(e.into());
+            }
+            self.recovering.insert(addr);
+        }
+        None
+    }
+    fn serve_waiters(&mut self, addr: SocketAddr) {
+        let error = self.check_blacklist(addr);
+        while self.waiters.get(&addr).is_some_and(|w| !w.is_empty()) {
+            let future = if let Some(ref e) = error {
+                if let Some(breaker) = self.circuit_breakers.get_mut(&addr) {
+                    breaker.cancel();
+                }
+                PooledConnection::failed(e.clone())
+            } else if let Some(future) = self.acquire_connection(addr) {
+                future
+            } else {
+                break;
+            };
+            let mut future = Some(future);
+            while let Some(waiter) = self.waiters.get_mut(&addr).and_then(|w| w.pop_front()) {
+                match waiter.send(future.take().expect("Never fails")) {
+                    Ok(()) => break,
+                    Err(SendError(f)) => {
+                        // The waiter has gone (e.g., the call has been dropped due to its timeout).
+                        if let Some(breaker) = self.circuit_breakers.get_mut(&addr) {
+                            breaker.cancel();
+                        }
+                        future = Some(f);
+                    }
+                }
+            }
+            if let Some(future) = future {
+                self.restore_connection(addr, future);
             }
         }
+        if self.waiters.get(&addr).is_some_and(|w| w.is_empty()) {
+            self.waiters.remove(&addr);
+        }
     }
-    fn acquire_connection(&mut self, addr: SocketAddr) -> PooledConnection {
+    /// Takes back the connection which could not be handed to any waiter.
+    fn restore_connection(&mut self, addr: SocketAddr, mut connection: PooledConnection) {
+        if let Some(ref mut lease) = connection.lease {
+            // NOTE: Prevents the lease from reporting that the connection has been closed.
+            lease.released = true;
+        } else {
+            // The connection has not been acquired (e.g., the address is blacklisted).
+            return;
+        }
+        if let Phase::A(ref mut idle) = connection.phase {
+            if let Ok(Async::Ready(idle)) = idle.poll() {
+                self.stats.reused_connections -= 1;
+                self.release_connection(addr, idle);
+                return;
+            }
+        }
+        self.decrement_open_connections(addr);
+    }
+    fn acquire_connection(&mut self, addr: SocketAddr) -> Option<PooledConnection> {
+        self.drop_expired_connections();
         let lower = ConnectionId::new(addr, 0);
-        if let Some(id) = self.connections
+        while let Some(id) = self.connections
             .range((Bound::Included(lower), Bound::Unbounded))
             .map(|(id, _)| id)
             .cloned()
@@ -185,22 +767,54 @@ impl RpcClientPool {
             .and_then(|id| if id.addr == addr { Some(id) } else { None })
         {
             self.lru_queue.remove(&id.seq_no);
-            let connection = self.connections.remove(&id).expect("Never fails");
-            let phase = Phase::A(futures::finished(connection));
-            PooledConnection {
-                phase,
-                on_error: None,
-            }
-        } else {
-            let phase = Phase::B(
-                miasht::client::Client::new()
-                    .connect(addr)
-                    .timeout_after(self.connect_timeout),
-            );
-            PooledConnection {
-                phase,
-                on_error: Some((addr, self.command_tx.clone())),
+            let mut idle = self.connections.remove(&id).expect("Never fails");
+            if client::is_alive(&mut idle.connection) {
+                self.stats.reused_connections += 1;
+                let phase = Phase::A(futures::finished(idle.connection));
+                return Some(PooledConnection {
+                    phase,
+                    notify: None,
+                    lease: Some(self.lease(addr)),
+                });
             }
+            self.decrement_open_connections(addr);
+        }
+
+        let open_connections = self.open_connections.entry(addr).or_insert(0);
+        if *open_connections >= self.max_connections_per_addr {
+            return None;
+        }
+        *open_connections += 1;
+        let lease = self.lease(addr);
+        Some(self.connect(addr, Some(lease)))
+    }
+    fn connect(&self, addr: SocketAddr, lease: Option<Lease>) -> PooledConnection {
+        let phase = Phase::B(
+            miasht::client::Client::new()
+                .connect(addr)
+                .timeout_after(self.connect_timeout),
+        );
+        PooledConnection {
+            phase,
+            notify: Some((addr, self.command_tx.clone())),
+            lease,
+        }
+    }
+    fn lease(&self, addr: SocketAddr) -> Lease {
+        Lease {
+            addr,
+            command_tx: self.command_tx.clone(),
+            released: false,
+            acquired_at: Instant::now(),
+            outcome: None,
+        }
+    }
+    fn decrement_open_connections(&mut self, addr: SocketAddr) {
+        if let Some(count) = self.open_connections.get_mut(&addr) {
+            *count -= 1;
+        }
+        if self.open_connections.get(&addr) == Some(&0) {
+            self.open_connections.remove(&addr);
         }
     }
     fn release_connection(&mut self, addr: SocketAddr, connection: TcpConnection) {
@@ -208,9 +822,49 @@ impl RpcClientPool {
         self.seq_no += 1;
 
         self.lru_queue.insert(id.seq_no, id.addr);
-        self.connections.insert(id, connection);
+        let released_at = Instant::now();
+        let idle = IdleConnection {
+            connection,
+            released_at,
+        };
+        self.connections.insert(id, idle);
+        self.drop_exceeded_idle_connections(addr);
         self.drop_exceeded_lru_connections();
     }
+    fn drop_connection(&mut self, id: ConnectionId) {
+        self.lru_queue.remove(&id.seq_no);
+        self.connections.remove(&id);
+        self.decrement_open_connections(id.addr);
+    }
+    fn drop_expired_connections(&mut self) {
+        let idle_timeout = if let Some(timeout) = self.idle_timeout {
+            timeout
+        } else {
+            return;
+        };
+        while let Some(id) = self.lru_queue
+            .iter()
+            .map(|(seq_no, addr)| ConnectionId::new(*addr, *seq_no))
+            .nth(0)
+        {
+            if self.connections[&id].released_at.elapsed() <= idle_timeout {
+                break;
+            }
+            self.drop_connection(id);
+        }
+    }
+    fn drop_exceeded_idle_connections(&mut self, addr: SocketAddr) {
+        let lower = ConnectionId::new(addr, 0);
+        let ids = self.connections
+            .range((Bound::Included(lower), Bound::Unbounded))
+            .map(|(id, _)| *id)
+            .take_while(|id| id.addr == addr)
+            .collect::<Vec<_>>();
+        let exceeded = ids.len().saturating_sub(self.max_idle_connections_per_addr);
+        for id in ids.into_iter().take(exceeded) {
+            self.drop_connection(id);
+        }
+    }
     fn drop_exceeded_lru_connections(&mut self) {
         while self.connections.len() > self.pool_size {
             let id = self.lru_queue
@@ -218,8 +872,23 @@ impl RpcClientPool {
                 .map(|(seq_no, addr)| ConnectionId::new(*addr, *seq_no))
                 .nth(0)
                 .expect("Never failes");
-            self.lru_queue.remove(&id.seq_no);
-            self.connections.remove(&id);
+            self.drop_connection(id);
+        }
+    }
+    fn poll_sweep_timer(&mut self) {
+        let idle_timeout = if let Some(timeout) = self.idle_timeout {
+            timeout
+        } else {
+            return;
+        };
+        loop {
+            let timer = self.sweep_timer
+                .get_or_insert_with(|| timer::timeout(idle_timeout));
+            if let Ok(Async::NotReady) = timer.poll() {
+                break;
+            }
+            self.sweep_timer = None;
+            self.drop_expired_connections();
         }
     }
 }
@@ -230,6 +899,7 @@ impl Future for RpcClientPool {
         while let Async::Ready(command) = self.command_rx.poll().expect("Never fails") {
             self.handle_command(command.expect("Never fails"));
         }
+        self.poll_sweep_timer();
         Ok(Async::NotReady)
     }
 }
@@ -238,6 +908,10 @@ impl Future for RpcClientPool {
 #[derive(Debug, Clone)]
 pub struct RpcClientPoolHandle {
     command_tx: mpsc::Sender<Command>,
+    blacklist: SharedBlacklist,
+    options: CallOptions,
+    retry_policy: RetryPolicy,
+    base_path: Option<EntryPoint>,
 }
 impl RpcClientPoolHandle {
     /// Acquires a RPC client from the pool.
@@ -247,16 +921,87 @@ impl RpcClientPoolHandle {
         PooledRpcClient { addr, handle: self }
     }
 
-    fn acquire_connection(&self, addr: SocketAddr) -> AcquireConnection {
+    /// Makes a client which balances calls across `addrs` by using `strategy`.
+    ///
+    /// Blacklisted addresses are skipped.
+    pub fn balanced_client(
+        &self,
+        addrs: Vec<SocketAddr>,
+        strategy: BalanceStrategy,
+    ) -> BalancedRpcClient {
+        BalancedRpcClient::new(self.clone(), addrs, strategy)
+    }
+
+    /// Sets the base path which is prepended to the entry points of procedures
+    /// called via this handle (including balanced and retried calls).
+    ///
+    /// This is useful for sharing a pool among the servers which mount procedures
+    /// under different prefixes (see `RpcServerBuilder::mount`).
+    ///
+    /// If `base_path` contains non-literal segments (i.e., variables or a tail),
+    /// this will return an `ErrorKind::Invalid` error.
+    pub fn set_base_path(&mut self, base_path: EntryPoint) -> Result<()> {
+        track_assert!(
+            base_path.is_literal(),
+            ErrorKind::Invalid,
+            "A base path must consist of only literal segments: base_path={:?}",
+            base_path
+        );
+        self.base_path = Some(base_path);
+        Ok(())
+    }
+
+    /// Returns `true` if the address is blacklisted at the moment.
+    pub(crate) fn is_blacklisted(&self, addr: SocketAddr) -> bool {
+        self.blacklist
+            .lock()
+            .ok()
+            .and_then(|blacklist| blacklist.get(&addr).cloned())
+            .is_some_and(|until| until > SystemTime::now())
+    }
+
+    /// Returns a snapshot of the statistics of the pool.
+    pub fn stats(&self) -> GetStats {
         let (reply, reply_rx) = oneshot::channel();
-        let command = Command::AcquireConnection { addr, reply };
-        let _ = self.command_tx.send(command);
-        let phase = Phase::A(reply_rx);
-        AcquireConnection { phase }
+        let _ = self.command_tx.send(Command::GetStats { reply });
+        GetStats { reply_rx }
+    }
+
+    /// Returns the blacklisted addresses and the times until which they are suspended.
+    pub fn blacklist(&self) -> GetBlacklist {
+        GetBlacklist {
+            stats: self.stats(),
+        }
+    }
+
+    /// Blacklists the address for the specified duration.
+    pub fn add_to_blacklist(&self, addr: SocketAddr, duration: Duration) {
+        let _ = self
+            .command_tx
+            .send(Command::AddToBlacklist { addr, duration });
+    }
+
+    /// Removes the address from the blacklist and resets its failure count.
+    pub fn remove_from_blacklist(&self, addr: SocketAddr) {
+        let _ = self.command_tx.send(Command::RemoveFromBlacklist { addr });
     }
-    fn release_connection(&self, addr: SocketAddr, connection: TcpConnection) {
-        let command = Command::ReleaseConnection { addr, connection };
+
+    /// Removes all the addresses from the blacklist.
+    pub fn clear_blacklist(&self) {
+        let _ = self.command_tx.send(Command::ClearBlacklist);
+    }
+
+    fn acquire_connection(
+        &self,
+        addr: SocketAddr,
+        reuse: bool,
+        lease: LeaseSlot,
+    ) -> AcquireConnection {
+        let (reply, reply_rx) = oneshot::channel();
+        let command = Command::AcquireConnection { addr, reuse, reply };
         let _ = self.command_tx.send(command);
+        let phase = Phase::A(reply_rx);
+        AcquireConnection { phase, lease }
     }
 }
 
@@ -273,32 +1018,92 @@ impl<'a> PooledRpcClient<'a> {
     where
         P: Procedure,
     {
-        let future = self.handle.acquire_connection(self.addr);
-        let inner = CallInner {
-            request: Some(request),
-            phase: Phase::A(Box::new(future)),
-        };
-        let future = Call {
-            inner,
-            addr: self.addr,
-            handle: self.handle.clone(),
+        self.call_with_options::<P>(request, CallOptions::new())
+    }
+
+    /// Issues an RPC request with the call specific options.
+    pub fn call_with_options<P>(&self, request: P::Request, options: CallOptions) -> Call<P>
+    where
+        P: Procedure,
+    {
+        let lease = LeaseSlot::default();
+        let future = self.handle.acquire_connection(self.addr, true, lease.clone());
+        let handle = self.handle.clone();
+        let addr = self.addr;
+        let reconnect_lease = lease.clone();
+        let reconnect: Reconnect = Box::new(move || {
+            let future = handle.acquire_connection(addr, false, reconnect_lease);
+            Box::new(future.map(|(connection, _)| connection))
+        });
+        let options = options.or(&self.handle.options);
+        let entry_point = if let Some(ref base_path) = self.handle.base_path {
+            P::entry_point().with_prefix(base_path)
+        } else {
+            P::entry_point()
         };
+        let inner = CallInner::new(
+            request,
+            entry_point,
+            Box::new(future),
+            reconnect,
+            options,
+        );
+        let future = Call { inner, lease };
         future
     }
+
+    /// Issues an RPC request which will be retried according to the retry policy
+    /// (see `RpcClientPool::set_retry_policy`).
+    pub fn call_with_retry<P>(&self, request: P::Request) -> RetryCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+    {
+        self.call_with_retry_and_options::<P>(request, CallOptions::new())
+    }
+
+    /// Issues an RPC request which will be retried according to the retry policy
+    /// with the call specific options.
+    ///
+    /// The total timeout (if any) is applied to the whole call including retries.
+    pub fn call_with_retry_and_options<P>(
+        &self,
+        request: P::Request,
+        options: CallOptions,
+    ) -> RetryCall<P>
+    where
+        P: Procedure,
+        P::Request: Clone,
+    {
+        let caller = Caller::Pool(self.handle.clone(), self.addr);
+        let options = options.or(&self.handle.options);
+        let policy = self.handle.retry_policy.clone();
+        RetryCall::new(caller, request, policy, options)
+    }
 }
 
 #[derive(Debug)]
 struct AcquireConnection {
     phase: Phase<oneshot::Receiver<PooledConnection>, PooledConnection>,
+    lease: LeaseSlot,
 }
 impl Future for AcquireConnection {
-    type Item = TcpConnection;
+    type Item = (TcpConnection, bool);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
                 Phase::A(future) => Phase::B(future),
-                Phase::B(connection) => return Ok(Async::Ready(connection)),
+                Phase::B(connection) => {
+                    if let Phase::B(ref mut future) = self.phase {
+                        if let (Some(lease), Ok(mut slot)) =
+                            (future.lease.take(), self.lease.lock())
+                        {
+                            *slot = Some(lease);
+                        }
+                    }
+                    return Ok(Async::Ready(connection));
+                }
                 _ => unreachable!(),
             };
             self.phase = next;
@@ -310,8 +1115,7 @@ impl Future for AcquireConnection {
 /// A `Future` which represents an RPC invocation.
 pub struct Call<P: Procedure> {
     inner: CallInner<P>,
-    addr: SocketAddr,
-    handle: RpcClientPoolHandle,
+    lease: LeaseSlot,
 }
 impl<P> Future for Call<P>
 where
@@ -320,11 +1124,290 @@ where
     type Item = P::Response;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Async::Ready((response, connection)) = track!(self.inner.poll())? {
-            self.handle.release_connection(self.addr, connection);
-            Ok(Async::Ready(response))
+        Ok(track!(self.poll_with_metadata())?.map(|(response, _)| response))
+    }
+}
+impl<P> Call<P>
+where
+    P: Procedure,
+{
+    /// Converts into a future which also results in the metadata of the HTTP response.
+    pub fn with_metadata(self) -> CallWithMetadata<P> {
+        CallWithMetadata(self)
+    }
+
+    /// Polls the call and returns the RPC response with the metadata of the HTTP response.
+    pub(crate) fn poll_with_metadata(&mut self) -> Poll<(P::Response, ResponseMetadata), Error> {
+        let polled = self.inner.poll();
+        if polled.is_err() {
+            if let Some(lease) = self.lease.lock().ok().as_mut().and_then(|l| l.as_mut()) {
+                lease.set_failure();
+            }
+        }
+        if let Async::Ready((response, metadata, connection)) = track!(polled)? {
+            let lease = self.lease.lock().ok().and_then(|mut lease| lease.take());
+            if let Some(mut lease) = lease {
+                lease.set_response(metadata.status());
+                if let Some(connection) = connection {
+                    lease.release(connection);
+                }
+            }
+            Ok(Async::Ready((response, metadata)))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
+
+/// A `Future` which represents an RPC invocation resulting in the response with its metadata.
+pub struct CallWithMetadata<P: Procedure>(Call<P>);
+impl<P> Future for CallWithMetadata<P>
+where
+    P: Procedure,
+{
+    type Item = (P::Response, ResponseMetadata);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.0.poll_with_metadata())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fibers::sync::oneshot::MonitorError;
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+
+    use test_util::*;
+    use super::*;
+
+    #[test]
+    fn base_path_works() {
+        let mut procedures = server_builder();
+        procedures.register(NamedHelloHandler("foo"), Hello).unwrap();
+        let mut builder = server_builder();
+        builder
+            .mount(&htrpc_entry_point!["v1", "greeting"], procedures)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut pool = RpcClientPool::new();
+        pool.set_base_path(htrpc_entry_point!["v1", "greeting"]).unwrap();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(hello("world")));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"foo"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let call = handle.client(addr).call_with_retry::<Hello>(hello("world"));
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"foo"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let client = handle.balanced_client(vec![addr], BalanceStrategy::RoundRobin);
+        let monitor = executor.spawn_monitor(client.call::<Hello>(hello("world")));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"foo"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        // The base path of a handle can be overridden
+        let mut handle = handle.clone();
+        handle.set_base_path(htrpc_entry_point!["v2"]).unwrap();
+        let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(hello("world")));
+        let result = executor.run_future(monitor).unwrap();
+        assert!(result.is_err(), "{:?}", result.map(|_| ()));
+
+        // Non-literal base paths are rejected
+        let e = handle.set_base_path(htrpc_entry_point!["v1", _]).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Invalid);
+        let mut pool = RpcClientPool::new();
+        let e = pool.set_base_path(htrpc_entry_point!["v1", *]).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Invalid);
+    }
+
+    #[test]
+    fn limits_work() {
+        let mut builder = server_builder();
+        builder
+            .register_with_context(PeerPortHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut pool = RpcClientPool::new();
+        pool.set_max_connections_per_addr(1);
+        pool.set_idle_timeout(Duration::from_millis(100));
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let request = || hello("world");
+        let call_all = |executor: &mut InPlaceExecutor, count: usize| {
+            let monitors = (0..count)
+                .map(|_| executor.spawn_monitor(handle.client(addr).call::<Hello>(request())))
+                .collect::<Vec<_>>();
+            monitors
+                .into_iter()
+                .map(
+                    |monitor| match executor.run_future(monitor).unwrap().unwrap() {
+                        HelloResponse::Ok { body } => String::from_utf8(body).unwrap(),
+                        r => panic!("Unexpected response: {:?}", r),
+                    },
+                )
+                .collect::<Vec<_>>()
+        };
+
+        // Concurrent calls share the only connection.
+        let ports = call_all(&mut executor, 3);
+        assert!(ports.iter().all(|p| *p == ports[0]), "{:?}", ports);
+
+        // The idle connection is closed after the idle timeout.
+        let timeout = timer::timeout(Duration::from_millis(300));
+        executor.run_future(timeout).unwrap().unwrap();
+        let new_ports = call_all(&mut executor, 1);
+        assert_ne!(ports[0], new_ports[0]);
+    }
+
+    #[test]
+    fn dropped_waiter_works() {
+        let mut builder = server_builder();
+        builder
+            .register(DelayedHelloHandler(Duration::from_millis(200)), Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut pool = RpcClientPool::new();
+        pool.set_max_connections_per_addr(1);
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        // The second call waits for the connection used by the first one, then times out.
+        let call = handle.client(addr).call::<Hello>(hello("world"));
+        let first = executor.spawn_monitor(call);
+        let mut options = CallOptions::new();
+        options.set_timeout(Duration::from_millis(50));
+        let call = handle
+            .client(addr)
+            .call_with_options::<Hello>(hello("world"), options);
+        let second = executor.spawn_monitor(call);
+        assert!(executor.run_future(second).unwrap().is_err());
+        assert!(executor.run_future(first).unwrap().is_ok());
+
+        // The connection released by the first call is kept for subsequent calls.
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert_eq!(stats.idle_connections.get(&addr), Some(&1));
+        let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(hello("world")));
+        assert!(executor.run_future(monitor).unwrap().is_ok());
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert_eq!(stats.created_connections, 1);
+        assert_eq!(stats.reused_connections, 1);
+    }
+
+    #[test]
+    fn stats_work() {
+        let unavailable_addr = unused_addr();
+        let mut builder = server_builder();
+        builder
+            .register_with_context(PeerPortHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let pool = RpcClientPool::new();
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let request = || hello("world");
+        for _ in 0..2 {
+            let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(request()));
+            assert!(executor.run_future(monitor).unwrap().is_ok());
+        }
+        let call = handle.client(unavailable_addr).call::<Hello>(request());
+        let monitor = executor.spawn_monitor(call);
+        assert!(executor.run_future(monitor).unwrap().is_err());
+
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert_eq!(stats.idle_connections.get(&addr), Some(&1));
+        assert_eq!(stats.created_connections, 1);
+        assert_eq!(stats.reused_connections, 1);
+        assert_eq!(stats.connect_failures, 1);
+        assert_eq!(stats.connect_timeouts, 0);
+        assert!(stats.blacklist.contains_key(&unavailable_addr));
+        assert!(!stats.blacklist.contains_key(&addr));
+    }
+
+    #[test]
+    fn connect_timeout_works() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut pool = RpcClientPool::new();
+        pool.set_connect_timeout(Duration::from_millis(50));
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let server = unresponsive_server();
+        let call = handle.client(server.addr).call::<Hello>(hello("world"));
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let stats = executor.run_future(handle.stats()).unwrap().unwrap();
+        assert_eq!(stats.connect_timeouts, 1);
+    }
+
+    #[test]
+    fn blacklist_works() {
+        let unavailable_addr = unused_addr();
+        let mut builder = server_builder();
+        builder
+            .register(FlakyHelloHandler::new(usize::MAX), Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut pool = RpcClientPool::new();
+        pool.set_suspended_duration(Duration::from_millis(100));
+        pool.set_server_error_threshold(2);
+        let handle = pool.handle();
+        executor.spawn(pool);
+
+        let request = || hello("world");
+        let call = |executor: &mut InPlaceExecutor, addr| {
+            let monitor = executor.spawn_monitor(handle.client(addr).call::<Hello>(request()));
+            executor.run_future(monitor).unwrap()
+        };
+
+        // Consecutive server errors
+        assert!(call(&mut executor, addr).is_ok());
+        assert!(call(&mut executor, addr).is_ok());
+        assert!(call(&mut executor, addr).is_err());
+        let blacklist = executor.run_future(handle.blacklist()).unwrap().unwrap();
+        assert!(blacklist.contains_key(&addr));
+
+        // Manual control
+        handle.remove_from_blacklist(addr);
+        assert!(call(&mut executor, addr).is_ok());
+        handle.add_to_blacklist(addr, Duration::from_secs(60));
+        assert!(call(&mut executor, addr).is_err());
+        handle.clear_blacklist();
+        assert!(call(&mut executor, addr).is_ok());
+
+        // Escalating suspended durations
+        assert!(call(&mut executor, unavailable_addr).is_err());
+        let timeout = timer::timeout(Duration::from_millis(150));
+        executor.run_future(timeout).unwrap().unwrap();
+        assert!(call(&mut executor, unavailable_addr).is_err());
+        let blacklist = executor.run_future(handle.blacklist()).unwrap().unwrap();
+        let suspended = blacklist[&unavailable_addr]
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(suspended > Duration::from_millis(150), "{:?}", suspended);
+    }
+}
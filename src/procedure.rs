@@ -1,7 +1,10 @@
 use futures::Future;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
+use rfc7807::ProblemResponse;
 use types::HttpMethod;
+use RequestContext;
 
 /// Procedure definition.
 pub trait Procedure {
@@ -16,10 +19,25 @@ pub trait Procedure {
 
     /// The entry point of this procedure.
     fn entry_point() -> EntryPoint;
+
+    /// Returns `true` if this procedure is idempotent (i.e., it is safe to retry).
+    ///
+    /// The default implementation returns `true` if the method is
+    /// `GET`, `HEAD`, `PUT`, `DELETE` or `OPTIONS`.
+    fn is_idempotent() -> bool {
+        matches!(
+            Self::method(),
+            HttpMethod::Get
+                | HttpMethod::Head
+                | HttpMethod::Put
+                | HttpMethod::Delete
+                | HttpMethod::Options
+        )
+    }
 }
 
 /// This trait allows to handle RPC requests issued by clients.
-pub trait HandleRpc<P>: Clone + Send + 'static
+pub trait HandleRpc<P>: Clone + Send + Sync + 'static
 where
     P: Procedure,
 {
@@ -30,9 +48,79 @@ where
     fn handle_rpc(self, request: <P as Procedure>::Request) -> Self::Future;
 }
 
+/// This trait allows to handle RPC requests which may fail.
+///
+/// Unlike `HandleRpc`, the error of the resulting future is converted into
+/// an RPC response (e.g., `rfc7807::ProblemResponse`) by using the `IntoErrorResponse` trait.
+pub trait HandleFallibleRpc<P>: Clone + Send + Sync + 'static
+where
+    P: Procedure,
+{
+    /// The error type of this handler.
+    type Error: IntoErrorResponse;
+
+    /// The `Future` which represents the result of an invocation of the `handle_rpc` method.
+    type Future: Future<Item = <P as Procedure>::Response, Error = Self::Error> + Send + 'static;
+
+    /// Handles an RPC request issued by a client.
+    fn handle_rpc(self, request: <P as Procedure>::Request) -> Self::Future;
+}
+
+/// This trait allows to handle RPC requests together with their contexts
+/// (e.g., the address of the client and the raw headers).
+///
+/// Like `HandleFallibleRpc`, the error of the resulting future is converted into
+/// an RPC response by using the `IntoErrorResponse` trait.
+/// `NeverFail` can be used as the error type of infallible handlers.
+pub trait HandleRpcWithContext<P>: Clone + Send + Sync + 'static
+where
+    P: Procedure,
+{
+    /// The error type of this handler.
+    type Error: IntoErrorResponse;
+
+    /// The `Future` which represents the result of an invocation of the `handle_rpc` method.
+    type Future: Future<Item = <P as Procedure>::Response, Error = Self::Error> + Send + 'static;
+
+    /// Handles an RPC request issued by a client.
+    fn handle_rpc(
+        self,
+        context: &RequestContext,
+        request: <P as Procedure>::Request,
+    ) -> Self::Future;
+}
+
+/// This trait allows to convert an error of a handler into the corresponding RPC response.
+///
+/// The status code of the HTTP response is determined by the resulting RPC response.
+pub trait IntoErrorResponse {
+    /// The RPC response type.
+    type Response: RpcResponse + Send + 'static;
+
+    /// Converts into an RPC response.
+    fn into_error_response(self) -> Self::Response;
+
+    /// Converts into an RPC response which may contain the detailed information of the error.
+    ///
+    /// This is used instead of `into_error_response` if the server is running in debug mode
+    /// (see `RpcServerBuilder::set_debug_mode`).
+    ///
+    /// The default implementation is the same as `into_error_response`.
+    fn into_debug_error_response(self) -> Self::Response
+    where
+        Self: Sized,
+    {
+        self.into_error_response()
+    }
+}
+
 /// A marker type used to indicate that a future never fails.
-pub struct NeverFail {
-    _cannot_instantiate: (),
+pub enum NeverFail {}
+impl IntoErrorResponse for NeverFail {
+    type Response = ProblemResponse;
+    fn into_error_response(self) -> Self::Response {
+        match self {}
+    }
 }
 
 /// RPC Request.
@@ -58,9 +146,9 @@ pub trait RpcResponse: Serialize + for<'a> Deserialize<'a> {
 }
 
 /// The entry point definition of a procedure.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EntryPoint {
-    segments: &'static [PathSegment],
+    segments: Cow<'static, [PathSegment]>,
 }
 impl EntryPoint {
     /// Makes a new `EntryPoint` instance.
@@ -72,47 +160,131 @@ impl EntryPoint {
     /// ```
     /// # #[macro_use]
     /// # extern crate htrpc;
+    /// use std::borrow::Cow;
     /// use htrpc::types::{EntryPoint, PathSegment};
     ///
     /// # fn main() {
-    /// static SEGMENTS: &[PathSegment] =
-    ///     &[PathSegment::Val("foo"), PathSegment::Var, PathSegment::Val("baz")];
+    /// static SEGMENTS: &[PathSegment] = &[
+    ///     PathSegment::Val(Cow::Borrowed("foo")),
+    ///     PathSegment::Var,
+    ///     PathSegment::Val(Cow::Borrowed("baz")),
+    /// ];
     /// let p0 = EntryPoint::new(SEGMENTS);
     /// let p1 = htrpc_entry_point!["foo", _, "baz"];
     /// assert_eq!(p0, p1);
     /// # }
     /// ```
     pub fn new(segments: &'static [PathSegment]) -> Self {
-        EntryPoint { segments }
+        EntryPoint {
+            segments: Cow::Borrowed(segments),
+        }
+    }
+
+    /// Makes a new `EntryPoint` instance from segments built at runtime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate htrpc;
+    /// use htrpc::types::{EntryPoint, PathSegment};
+    ///
+    /// # fn main() {
+    /// let prefix = String::from("foo"); // e.g., read from a configuration file
+    /// let p0 = EntryPoint::from_segments(vec![
+    ///     PathSegment::Val(prefix.into()),
+    ///     PathSegment::Var,
+    ///     PathSegment::Val("baz".into()),
+    /// ]);
+    /// let p1 = htrpc_entry_point!["foo", _, "baz"];
+    /// assert_eq!(p0, p1);
+    /// # }
+    /// ```
+    pub fn from_segments(segments: Vec<PathSegment>) -> Self {
+        EntryPoint {
+            segments: Cow::Owned(segments),
+        }
     }
 
     /// Returns the segments in this entry point.
-    pub fn segments(&self) -> &'static [PathSegment] {
-        self.segments
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// Makes a new `EntryPoint` instance which has `prefix` in front of this entry point.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate htrpc;
+    /// # fn main() {
+    /// let p0 = htrpc_entry_point!["foo", _];
+    /// let p1 = p0.with_prefix(&htrpc_entry_point!["v1", "bar"]);
+    /// assert_eq!(p1, htrpc_entry_point!["v1", "bar", "foo", _]);
+    /// # }
+    /// ```
+    pub fn with_prefix(&self, prefix: &EntryPoint) -> Self {
+        let segments = prefix
+            .segments()
+            .iter()
+            .chain(self.segments())
+            .cloned()
+            .collect();
+        EntryPoint::from_segments(segments)
     }
 
     /// Counts variables in this entry point.
+    ///
+    /// A tail segment is counted as a variable.
     pub fn var_count(&self) -> usize {
         self.segments
             .iter()
-            .filter(|s| s == &&PathSegment::Var)
+            .filter(|s| s == &&PathSegment::Var || s == &&PathSegment::Tail)
             .count()
     }
+
+    /// Returns `true` if this entry point ends with a tail segment.
+    pub fn has_tail(&self) -> bool {
+        self.segments.last() == Some(&PathSegment::Tail)
+    }
+
+    /// Returns `true` if this entry point consists of only value (i.e., literal) segments.
+    pub fn is_literal(&self) -> bool {
+        self.segments.iter().all(|s| s.as_option().is_some())
+    }
+
+    /// Returns `true` if tail segments appear only at the end of this entry point.
+    pub fn is_valid(&self) -> bool {
+        self.segments
+            .iter()
+            .rev()
+            .skip(1)
+            .all(|s| s != &PathSegment::Tail)
+    }
 }
 
 /// Path segment which is used for constructing `EntryPoint`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum PathSegment {
     /// Value (i.e., constant) segment.
-    Val(&'static str),
+    Val(Cow<'static, str>),
 
     /// Variable (i.e., wildcard) segment.
     Var,
+
+    /// Tail segment which matches zero or more remaining segments.
+    ///
+    /// This must be the last segment of an entry point.
+    /// The corresponding value is represented as a `String` (the segments joined with `/`)
+    /// or a `Vec<String>`.
+    /// Empty segments (e.g., the ones in `a//b` and `a/`) are preserved.
+    Tail,
 }
 impl PathSegment {
     /// Converts to `Option`.
-    pub fn as_option(&self) -> Option<&'static str> {
-        if let PathSegment::Val(s) = *self {
+    pub fn as_option(&self) -> Option<&str> {
+        if let PathSegment::Val(ref s) = *self {
             Some(s)
         } else {
             None
@@ -127,9 +299,31 @@ mod test {
     #[test]
     fn it_works() {
         use self::PathSegment::*;
-        static SEGMENTS: &[PathSegment] = &[Val("foo"), Var, Val("baz")];
+        static SEGMENTS: &[PathSegment] =
+            &[Val(Cow::Borrowed("foo")), Var, Val(Cow::Borrowed("baz"))];
         let path0 = EntryPoint::new(SEGMENTS);
         let path1 = htrpc_entry_point!["foo", _, "baz"];
         assert_eq!(path0, path1);
+        assert_eq!(path0.var_count(), 1);
+        assert!(!path0.has_tail());
+
+        let path2 = htrpc_entry_point!["objects", _, *];
+        assert_eq!(path2.var_count(), 2);
+        assert!(path2.has_tail());
+        assert!(path2.is_valid());
+
+        static INVALID: &[PathSegment] = &[Tail, Val(Cow::Borrowed("foo"))];
+        assert!(!EntryPoint::new(INVALID).is_valid());
+    }
+
+    #[test]
+    fn owned_segments_work() {
+        use self::PathSegment::*;
+        let prefix = "foo".to_owned();
+        let path0 = EntryPoint::from_segments(vec![Val(prefix.into()), Var, Val("baz".into())]);
+        let path1 = htrpc_entry_point!["foo", _, "baz"];
+        assert_eq!(path0, path1);
+        assert_eq!(path0.segments()[0].as_option(), Some("foo"));
+        assert_eq!(path0.var_count(), 1);
     }
 }
@@ -0,0 +1,500 @@
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fibers::time::timer;
+use futures::{Async, Future, Poll};
+use handy_async::future::Phase;
+
+use {CallOptions, Error, ErrorKind, Procedure, ResponseMetadata, RpcClient};
+use client;
+use pool::{self, RpcClientPoolHandle};
+
+/// Retry policy of RPC calls.
+///
+/// A failed call is retried after a backoff delay which grows exponentially
+/// (`initial_backoff * 2^n`, capped at `max_backoff`).
+/// The actual delay is randomly chosen from the range `[delay / 2, delay]` (i.e., jitter).
+/// If a retryable response has a `Retry-After` header (in seconds),
+/// the delay is extended to that value (but is still capped at `max_backoff`).
+///
+/// By default, only idempotent procedures (see `Procedure::is_idempotent`) are retried.
+///
+/// If the total timeout of a call is specified (e.g., `CallOptions::set_timeout`),
+/// it is applied to the whole call including retries and backoff delays.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retryable_errors: Vec<ErrorKind>,
+    retry_io_errors: bool,
+    retryable_statuses: Vec<u16>,
+    retry_non_idempotent: bool,
+}
+impl RetryPolicy {
+    /// Makes a new `RetryPolicy` instance with the default settings.
+    ///
+    /// The defaults are as follows:
+    ///
+    /// - max attempts: `3`
+    /// - initial backoff: `100ms`
+    /// - max backoff: `10s`
+    /// - retryable errors: `ErrorKind::Timeout` and I/O errors (e.g., connection refused)
+    /// - retryable statuses: `503`
+    ///
+    /// Note that the other errors (e.g., calls refused because the destination is blacklisted
+    /// or its circuit breaker is open) are not retried by default.
+    pub fn new() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            retryable_errors: vec![ErrorKind::Timeout],
+            retry_io_errors: true,
+            retryable_statuses: vec![503],
+            retry_non_idempotent: false,
+        }
+    }
+
+    /// Makes a new `RetryPolicy` instance which never retries.
+    pub fn never() -> Self {
+        let mut policy = Self::new();
+        policy.set_max_attempts(1);
+        policy
+    }
+
+    /// Sets the maximum number of attempts (including the first one).
+    ///
+    /// `0` is treated as `1`.
+    pub fn set_max_attempts(&mut self, count: usize) {
+        self.max_attempts = count;
+    }
+
+    /// Sets the backoff delay before the first retry.
+    pub fn set_initial_backoff(&mut self, delay: Duration) {
+        self.initial_backoff = delay;
+    }
+
+    /// Sets the upper limit of the backoff delay.
+    pub fn set_max_backoff(&mut self, delay: Duration) {
+        self.max_backoff = delay;
+    }
+
+    /// Sets the kinds of errors which should be retried.
+    pub fn set_retryable_errors(&mut self, kinds: Vec<ErrorKind>) {
+        self.retryable_errors = kinds;
+    }
+
+    /// If `true` is specified, the errors caused by I/O failures
+    /// (e.g., connection refused or reset) are retried regardless of their kinds.
+    ///
+    /// The default value is `true`.
+    pub fn set_retry_io_errors(&mut self, enabled: bool) {
+        self.retry_io_errors = enabled;
+    }
+
+    /// Sets the HTTP status codes of responses which should be retried.
+    ///
+    /// If the last attempt results in such a status, the response is returned as is.
+    pub fn set_retryable_statuses(&mut self, statuses: Vec<u16>) {
+        self.retryable_statuses = statuses;
+    }
+
+    /// If `true` is specified, non idempotent procedures (e.g., `POST`) are also retried.
+    ///
+    /// The default value is `false`.
+    pub fn set_retry_non_idempotent(&mut self, enabled: bool) {
+        self.retry_non_idempotent = enabled;
+    }
+
+    fn is_retryable_procedure<P: Procedure>(&self) -> bool {
+        self.retry_non_idempotent || P::is_idempotent()
+    }
+
+    fn is_retryable_error(&self, error: &Error) -> bool {
+        self.retryable_errors.contains(error.kind())
+            || (self.retry_io_errors && error.concrete_cause::<io::Error>().is_some())
+    }
+
+    fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    /// Returns the delay before the `retry`-th retry (`retry` starts from `0`).
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff));
+        delay / 2 + delay.mul_f64(jitter() / 2.0)
+    }
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a random number in the range `[0.0, 1.0)`.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u32(d.subsec_nanos());
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Returns the value of the `Retry-After` header of the response.
+///
+/// Only the delay in seconds is supported (i.e., an HTTP date is ignored).
+fn retry_after(metadata: &ResponseMetadata) -> Option<Duration> {
+    let value = metadata.header("retry-after")?;
+    let seconds = ::std::str::from_utf8(value).ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[derive(Debug)]
+pub(crate) enum Caller {
+    Client(RpcClient),
+    Pool(RpcClientPoolHandle, SocketAddr),
+}
+
+enum Attempt<P: Procedure> {
+    Client(client::Call<P>),
+    Pool(pool::Call<P>),
+}
+impl<P> Future for Attempt<P>
+where
+    P: Procedure,
+{
+    type Item = (P::Response, ResponseMetadata);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Attempt::Client(ref mut f) => track!(f.poll_with_metadata()),
+            Attempt::Pool(ref mut f) => track!(f.poll_with_metadata()),
+        }
+    }
+}
+
+/// A `Future` which represents an RPC invocation with retries.
+pub struct RetryCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    caller: Caller,
+    request: P::Request,
+    policy: RetryPolicy,
+    options: CallOptions,
+    deadline: Option<Instant>,
+    attempts: usize,
+    phase: Phase<Attempt<P>, timer::Timeout>,
+}
+impl<P> RetryCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    pub(crate) fn new(
+        caller: Caller,
+        request: P::Request,
+        policy: RetryPolicy,
+        options: CallOptions,
+    ) -> Self {
+        let mut caller = caller;
+        let deadline = options.timeout().map(|timeout| Instant::now() + timeout);
+        let attempt = Self::attempt(&mut caller, request.clone(), &options, deadline);
+        RetryCall {
+            caller,
+            request,
+            policy,
+            options,
+            deadline,
+            attempts: 1,
+            phase: Phase::A(attempt),
+        }
+    }
+
+    fn attempt(
+        caller: &mut Caller,
+        request: P::Request,
+        options: &CallOptions,
+        deadline: Option<Instant>,
+    ) -> Attempt<P> {
+        let mut options = options.clone();
+        if let Some(deadline) = deadline {
+            // NOTE: The total timeout is shared by all the attempts.
+            options.set_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        match *caller {
+            Caller::Client(ref mut client) => {
+                Attempt::Client(client.call_with_options::<P>(request, options))
+            }
+            Caller::Pool(ref handle, addr) => {
+                Attempt::Pool(handle.client(addr).call_with_options::<P>(request, options))
+            }
+        }
+    }
+
+    /// Returns the backoff delay before the next attempt if the call can be retried.
+    ///
+    /// `retry_after` is the value of the `Retry-After` header of the last response.
+    fn next_backoff(&self, retry_after: Option<Duration>) -> Option<Duration> {
+        if self.attempts >= self.policy.max_attempts || !self.policy.is_retryable_procedure::<P>() {
+            return None;
+        }
+        let mut backoff = self.policy.backoff(self.attempts - 1);
+        if let Some(retry_after) = retry_after {
+            backoff = cmp::max(backoff, cmp::min(retry_after, self.policy.max_backoff));
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() + backoff >= deadline {
+                return None;
+            }
+        }
+        Some(backoff)
+    }
+}
+impl<P> Future for RetryCall<P>
+where
+    P: Procedure,
+    P::Request: Clone,
+{
+    type Item = P::Response;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.phase.poll() {
+                Err(Phase::A(e)) => match self.next_backoff(None) {
+                    Some(backoff) if self.policy.is_retryable_error(&e) => {
+                        Phase::B(timer::timeout(backoff))
+                    }
+                    _ => return Err(track!(e, "attempts={}", self.attempts)),
+                },
+                Err(e) => return Err(track!(Error::from(e))),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Phase::A((response, metadata)))) => {
+                    match self.next_backoff(retry_after(&metadata)) {
+                        Some(backoff) if self.policy.is_retryable_status(metadata.status()) => {
+                            Phase::B(timer::timeout(backoff))
+                        }
+                        _ => return Ok(Async::Ready(response)),
+                    }
+                }
+                Ok(Async::Ready(Phase::B(()))) => {
+                    self.attempts += 1;
+                    let request = self.request.clone();
+                    Phase::A(Self::attempt(
+                        &mut self.caller,
+                        request,
+                        &self.options,
+                        self.deadline,
+                    ))
+                }
+                Ok(Async::Ready(_)) => unreachable!(),
+            };
+            self.phase = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use fibers::sync::oneshot::MonitorError;
+
+    use pool::RpcClientPool;
+    use test_util::*;
+    use super::*;
+
+    #[test]
+    fn backoff_works() {
+        let mut policy = RetryPolicy::new();
+        policy.set_initial_backoff(Duration::from_millis(100));
+        policy.set_max_backoff(Duration::from_millis(300));
+        for _ in 0..10 {
+            let d = policy.backoff(0);
+            assert!(Duration::from_millis(50) <= d && d <= Duration::from_millis(100));
+            let d = policy.backoff(1);
+            assert!(Duration::from_millis(100) <= d && d <= Duration::from_millis(200));
+            let d = policy.backoff(100);
+            assert!(Duration::from_millis(150) <= d && d <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn retry_works() {
+        let handler = FlakyHelloHandler::new(2);
+        let calls = handler.calls.clone();
+        let mut builder = server_builder();
+        builder.register(handler.clone(), Hello).unwrap();
+        builder.register(handler, PostHello).unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let request = hello("world");
+        let mut policy = RetryPolicy::new();
+        policy.set_initial_backoff(Duration::from_millis(10));
+
+        // Idempotent
+        let mut client = RpcClient::new(addr);
+        client.set_retry_policy(policy.clone());
+        let monitor = executor.spawn_monitor(client.call_with_retry::<Hello>(request.clone()));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { .. } => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+        // Not enough attempts
+        let mut few_attempts = policy.clone();
+        few_attempts.set_max_attempts(2);
+        client.set_retry_policy(few_attempts);
+        let monitor = executor.spawn_monitor(client.call_with_retry::<Hello>(request.clone()));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::ServiceUnavailable { .. } => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 2);
+
+        // Non idempotent
+        client.set_retry_policy(policy.clone());
+        let call = client.call_with_retry::<PostHello>(request.clone());
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::ServiceUnavailable { .. } => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+
+        // Pool
+        policy.set_retry_non_idempotent(true);
+        let mut pool = RpcClientPool::new();
+        pool.set_retry_policy(policy);
+        let handle = pool.handle();
+        executor.spawn(pool);
+        let call = handle.client(addr).call_with_retry::<PostHello>(request);
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { .. } => {}
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retry_after_works() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut call = |policy: &RetryPolicy| {
+            let mut builder = server_builder();
+            let handler = BusyHelloHandler::new(Duration::from_secs(1));
+            builder.register_fallible(handler.clone(), Hello).unwrap();
+            let addr = spawn_server(builder).addr;
+
+            let mut client = RpcClient::new(addr);
+            client.set_retry_policy(policy.clone());
+            let started_at = Instant::now();
+            let monitor = executor.spawn_monitor(client.call_with_retry::<Hello>(hello("world")));
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::Ok { .. } => {}
+                r => panic!("Unexpected response: {:?}", r),
+            }
+            assert_eq!(handler.calls.load(Ordering::SeqCst), 2);
+            started_at.elapsed()
+        };
+
+        let mut policy = RetryPolicy::new();
+        policy.set_initial_backoff(Duration::from_millis(10));
+        assert!(call(&policy) >= Duration::from_secs(1));
+
+        // `Retry-After` is capped at the maximum backoff
+        policy.set_max_backoff(Duration::from_millis(100));
+        assert!(call(&policy) < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_deadline_works() {
+        let mut builder = server_builder();
+        builder.register(SlowHelloHandler, Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut policy = RetryPolicy::new();
+        policy.set_max_attempts(10);
+        policy.set_initial_backoff(Duration::from_millis(10));
+        let mut options = CallOptions::new();
+        options.set_timeout(Duration::from_millis(200));
+
+        // Client
+        let mut client = RpcClient::new(addr);
+        client.set_retry_policy(policy.clone());
+        let started_at = Instant::now();
+        let call = client.call_with_retry_and_options::<Hello>(hello("slow"), options.clone());
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        // Pool
+        let mut pool = RpcClientPool::new();
+        pool.set_retry_policy(policy);
+        pool.set_timeout(Duration::from_millis(200));
+        let handle = pool.handle();
+        executor.spawn(pool);
+        let started_at = Instant::now();
+        let call = handle.client(addr).call_with_retry::<Hello>(hello("slow"));
+        let monitor = executor.spawn_monitor(call);
+        match executor.run_future(monitor).unwrap() {
+            Err(MonitorError::Failed(e)) => assert_eq!(*e.kind(), ErrorKind::Timeout),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retryable_errors_work() {
+        let unavailable_addr = unused_addr();
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut policy = RetryPolicy::new();
+        policy.set_initial_backoff(Duration::from_millis(200));
+        let mut call = |policy: &RetryPolicy| {
+            let mut client = RpcClient::new(unavailable_addr);
+            client.set_retry_policy(policy.clone());
+            let started_at = Instant::now();
+            let monitor = executor.spawn_monitor(client.call_with_retry::<Hello>(hello("world")));
+            assert!(executor.run_future(monitor).unwrap().is_err());
+            started_at.elapsed()
+        };
+
+        // I/O errors (i.e., connection refused) are retried.
+        assert!(call(&policy) >= Duration::from_millis(100));
+
+        policy.set_retry_io_errors(false);
+        assert!(call(&policy) < Duration::from_millis(100));
+
+        // Blacklisted addresses are not retried.
+        let mut policy = RetryPolicy::new();
+        policy.set_initial_backoff(Duration::from_secs(10));
+        let mut pool = RpcClientPool::new();
+        pool.set_retry_policy(policy);
+        let handle = pool.handle();
+        executor.spawn(pool);
+        handle.add_to_blacklist(unavailable_addr, Duration::from_secs(60));
+        let started_at = Instant::now();
+        let call = handle
+            .client(unavailable_addr)
+            .call_with_retry::<Hello>(hello("world"));
+        let monitor = executor.spawn_monitor(call);
+        assert!(executor.run_future(monitor).unwrap().is_err());
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+}
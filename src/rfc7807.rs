@@ -4,11 +4,12 @@
 use serdeconv;
 use std::error;
 use std::fmt;
+use std::time::Duration;
 use trackable::Trackable;
 use url::Url;
 
 use types::HttpStatus;
-use RpcResponse;
+use {Error, IntoErrorResponse, RpcResponse};
 
 /// An RPC response that comforms [RFC 7807](RFC 7807).
 ///
@@ -40,6 +41,18 @@ impl ProblemResponse {
     pub fn set_problem(&mut self, problem: Problem) {
         self.body = problem;
     }
+
+    /// Returns the value of the `Retry-After` header of this response.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.header.retry_after.map(Duration::from_secs)
+    }
+
+    /// Sets the value of the `Retry-After` header of this response.
+    ///
+    /// Note that the fractional part of `delay` is truncated.
+    pub fn set_retry_after(&mut self, delay: Duration) {
+        self.header.retry_after = Some(delay.as_secs());
+    }
 }
 impl RpcResponse for ProblemResponse {
     fn body(&mut self) -> Box<dyn AsRef<[u8]> + Send + 'static> {
@@ -58,11 +71,14 @@ impl RpcResponse for ProblemResponse {
 struct ProblemHeader {
     #[serde(rename = "content-type")]
     content_type: ContentTypeProblemJson,
+    #[serde(rename = "retry-after")]
+    retry_after: Option<u64>,
 }
 impl ProblemHeader {
     pub fn new() -> Self {
         ProblemHeader {
             content_type: ContentTypeProblemJson,
+            retry_after: None,
         }
     }
 }
@@ -146,13 +162,19 @@ impl Problem {
         ProblemResponse::new(self)
     }
 
-    fn get_status_code(&self) -> u16 {
+    pub(crate) fn get_status_code(&self) -> u16 {
         match *self {
             Problem::AboutBlank(ref p) => p.status,
             Problem::Trackable(ref p) => p.status,
         }
     }
 }
+impl IntoErrorResponse for Problem {
+    type Response = ProblemResponse;
+    fn into_error_response(self) -> Self::Response {
+        self.into_response()
+    }
+}
 impl Default for Problem {
     fn default() -> Self {
         Problem::about_blank(HttpStatus::InternalServerError)
@@ -169,6 +191,20 @@ impl From<TrackableProblem> for Problem {
     }
 }
 
+/// `Error` is converted into an `AboutBlankProblem` with the status code
+/// `500 Internal Server Error`.
+///
+/// In debug mode, it is converted into a `TrackableProblem` which contains the tracking history.
+impl IntoErrorResponse for Error {
+    type Response = ProblemResponse;
+    fn into_error_response(self) -> Self::Response {
+        Problem::about_blank(HttpStatus::InternalServerError).into_response()
+    }
+    fn into_debug_error_response(self) -> Self::Response {
+        Problem::trackable(HttpStatus::InternalServerError, self).into_response()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename = "application/problem+json")]
 struct ContentTypeProblemJson;
@@ -1,12 +1,15 @@
 use fibers::net::TcpStream;
 use futures::Future;
 use miasht::server::{Request, Response};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use url::Url;
 
 use procedure::EntryPoint;
-use types::{HttpMethod, HttpStatus};
+use server::ServerOptions;
+use types::HttpMethod;
 use {Error, ErrorKind, Result};
 
 type HandleHttpRequestResult = Box<
@@ -14,35 +17,69 @@ type HandleHttpRequestResult = Box<
         + Send
         + 'static,
 >;
-type HandleHttpRequest =
-    Box<dyn Fn(Url, Request<TcpStream>) -> HandleHttpRequestResult + Send + 'static>;
+type HandleHttpRequest = Box<
+    dyn Fn(
+            &EntryPoint,
+            Url,
+            Request<TcpStream>,
+            &ServerOptions,
+            SocketAddr,
+        ) -> HandleHttpRequestResult
+        + Send
+        + Sync
+        + 'static,
+>;
 
 #[derive(Clone)]
 pub struct Router {
-    trie: Arc<Trie>,
+    trie: Arc<Trie<Route>>,
 }
-unsafe impl Send for Router {}
 impl Router {
     pub fn route(
         &self,
         url: &Url,
         request: &Request<TcpStream>,
-    ) -> ::std::result::Result<&HandleHttpRequest, HttpStatus> {
-        let mut trie = self.trie.root();
-        for segment in url.path_segments().expect("Never fails") {
-            if let Some(child) = trie.get_child(segment) {
-                trie = child;
-            } else {
-                return Err(HttpStatus::NotFound);
-            }
-        }
-        trie.get_value(request.method())
-            .ok_or(HttpStatus::MethodNotAllowed)
+    ) -> ::std::result::Result<&Route, RouteError> {
+        let segments = url
+            .path_segments()
+            .expect("Never fails")
+            .collect::<Vec<_>>();
+        self.trie.lookup(&segments, request.method())
+    }
+}
+
+/// The reasons why a request could not be routed to a handler.
+#[derive(Debug)]
+pub enum RouteError {
+    /// No procedure is registered for the path.
+    NotFound,
+
+    /// Some procedures are registered for the path, but none of them accepts the method.
+    ///
+    /// `allow` contains the methods which are registered for the path
+    /// (and `OPTIONS` which is automatically supported).
+    MethodNotAllowed { allow: Vec<HttpMethod> },
+}
+
+/// A handler of HTTP requests and the entry point at which it is registered.
+pub struct Route {
+    entry_point: EntryPoint,
+    handler: HandleHttpRequest,
+}
+impl Route {
+    pub fn handle(
+        &self,
+        url: Url,
+        request: Request<TcpStream>,
+        options: &ServerOptions,
+        peer_addr: SocketAddr,
+    ) -> HandleHttpRequestResult {
+        (self.handler)(&self.entry_point, url, request, options, peer_addr)
     }
 }
 
 pub struct RouterBuilder {
-    trie: Trie,
+    trie: Trie<Route>,
 }
 impl RouterBuilder {
     pub fn new() -> Self {
@@ -60,17 +97,47 @@ impl RouterBuilder {
         handler: H,
     ) -> Result<()>
     where
-        H: Send + 'static + Fn(Url, Request<TcpStream>) -> HandleHttpRequestResult,
+        H: Send
+            + Sync
+            + 'static
+            + Fn(
+                &EntryPoint,
+                Url,
+                Request<TcpStream>,
+                &ServerOptions,
+                SocketAddr,
+            ) -> HandleHttpRequestResult,
     {
-        track!(self.trie.insert(method, &entry_point, Box::new(handler)))?;
+        let route = Route {
+            entry_point,
+            handler: Box::new(handler),
+        };
+        track!(self.insert(method, route))
+    }
+    pub fn mount(&mut self, prefix: &EntryPoint, other: RouterBuilder) -> Result<()> {
+        track_assert!(
+            prefix.is_literal(),
+            ErrorKind::Invalid,
+            "A prefix must consist of only literal segments: prefix={:?}",
+            prefix
+        );
+        for (method, mut route) in other.trie.into_values() {
+            route.entry_point = route.entry_point.with_prefix(prefix);
+            track!(self.insert(method, route))?;
+        }
+        Ok(())
+    }
+    fn insert(&mut self, method: HttpMethod, route: Route) -> Result<()> {
+        let entry_point = route.entry_point.clone();
+        track!(self.trie.insert(method, &entry_point, route))?;
         Ok(())
     }
 }
 
-struct Trie {
-    root: TrieNode,
+struct Trie<T> {
+    root: TrieNode<T>,
 }
-impl Trie {
+impl<T> Trie<T> {
     pub fn new() -> Self {
         Trie {
             root: TrieNode::new(),
@@ -80,17 +147,23 @@ impl Trie {
         &mut self,
         method: HttpMethod,
         entry_point: &EntryPoint,
-        handler: HandleHttpRequest,
+        handler: T,
     ) -> Result<()> {
+        track_assert!(
+            entry_point.is_valid(),
+            ErrorKind::Invalid,
+            "A tail segment must be the last one: entry_point={:?}",
+            entry_point
+        );
         let mut node = &mut self.root;
         for segment in entry_point.segments() {
             use types::PathSegment::*;
-            let key = match *segment {
-                Val(s) => Some(s),
-                Var => None,
-            };
             let prev = node;
-            node = prev.children.entry(key).or_insert_with(|| TrieNode::new());
+            node = match *segment {
+                Val(ref s) => prev.literals.entry(s.clone()).or_insert_with(TrieNode::new),
+                Var => prev.var.get_or_insert_with(|| Box::new(TrieNode::new())),
+                Tail => prev.tail.get_or_insert_with(|| Box::new(TrieNode::new())),
+            };
         }
         track_assert!(
             !node.leafs.contains_key(&method),
@@ -102,29 +175,252 @@ impl Trie {
         node.leafs.insert(method, handler);
         Ok(())
     }
-    pub fn root(&self) -> &TrieNode {
-        &self.root
+
+    /// Looks up the value associated with the path and the method.
+    ///
+    /// A path may match more than one entry point
+    /// (e.g., `/users/me/posts` matches both `["users", "me", _]` and `["users", _, "posts"]`).
+    /// In that case, the candidates are ordered by specificity:
+    /// the entry point whose leftmost differing segment is more specific wins
+    /// (a literal is preferred to a variable, and a variable is preferred to a tail).
+    /// The most specific candidate which accepts `method` is selected.
+    ///
+    /// If no candidate accepts `method`, `RouteError::MethodNotAllowed` which
+    /// has the union of the allowed methods of all the candidates is returned.
+    pub fn lookup(
+        &self,
+        segments: &[&str],
+        method: HttpMethod,
+    ) -> ::std::result::Result<&T, RouteError> {
+        let mut candidates = Vec::new();
+        self.root.collect_matches(segments, &mut candidates);
+        if let Some(value) = candidates.iter().filter_map(|n| n.get_value(method)).next() {
+            return Ok(value);
+        }
+        if candidates.is_empty() {
+            return Err(RouteError::NotFound);
+        }
+        let mut allow = candidates
+            .iter()
+            .flat_map(|n| n.allowed_methods())
+            .collect::<Vec<_>>();
+        allow.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        allow.dedup();
+        Err(RouteError::MethodNotAllowed { allow })
+    }
+    pub fn into_values(self) -> Vec<(HttpMethod, T)> {
+        let mut values = Vec::new();
+        self.root.collect_values(&mut values);
+        values
     }
 }
 
-struct TrieNode {
-    children: HashMap<Option<&'static str>, TrieNode>,
-    leafs: HashMap<HttpMethod, HandleHttpRequest>,
+struct TrieNode<T> {
+    literals: HashMap<Cow<'static, str>, TrieNode<T>>,
+    var: Option<Box<TrieNode<T>>>,
+    tail: Option<Box<TrieNode<T>>>,
+    leafs: HashMap<HttpMethod, T>,
 }
-impl TrieNode {
+impl<T> TrieNode<T> {
     pub fn new() -> Self {
         TrieNode {
-            children: HashMap::new(),
+            literals: HashMap::new(),
+            var: None,
+            tail: None,
             leafs: HashMap::new(),
         }
     }
-    pub fn get_child<'a>(&'a self, segment: &str) -> Option<&'a Self> {
-        let segment: &'static str = unsafe { &*(segment as *const _) as _ };
-        self.children
-            .get(&Some(segment))
-            .or_else(|| self.children.get(&None))
+    pub fn get_children<'a>(&'a self, segment: &str) -> impl Iterator<Item = &'a Self> {
+        // NOTE: Literal children take precedence over variable ones.
+        self.literals
+            .get(segment)
+            .into_iter()
+            .chain(self.var.as_deref())
     }
-    pub fn get_value(&self, method: HttpMethod) -> Option<&HandleHttpRequest> {
+    pub fn collect_matches<'a>(&'a self, segments: &[&str], matches: &mut Vec<&'a Self>) {
+        if let Some((segment, rest)) = segments.split_first() {
+            for child in self.get_children(segment) {
+                child.collect_matches(rest, matches);
+            }
+        } else if !self.leafs.is_empty() {
+            matches.push(self);
+        }
+        if let Some(ref tail) = self.tail {
+            matches.push(tail);
+        }
+    }
+    pub fn collect_values(self, values: &mut Vec<(HttpMethod, T)>) {
+        values.extend(self.leafs);
+        for (_, child) in self.literals {
+            child.collect_values(values);
+        }
+        for child in self.var.into_iter().chain(self.tail) {
+            child.collect_values(values);
+        }
+    }
+    pub fn get_value(&self, method: HttpMethod) -> Option<&T> {
         self.leafs.get(&method)
     }
+    pub fn allowed_methods(&self) -> Vec<HttpMethod> {
+        let mut methods = self.leafs.keys().cloned().collect::<Vec<_>>();
+        if !self.leafs.contains_key(&HttpMethod::Options) {
+            methods.push(HttpMethod::Options);
+        }
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trie(entries: &[(HttpMethod, EntryPoint, &'static str)]) -> Trie<&'static str> {
+        let mut trie = Trie::new();
+        for &(method, ref entry_point, name) in entries {
+            trie.insert(method, entry_point, name).unwrap();
+        }
+        trie
+    }
+
+    #[test]
+    fn backtracking_works() {
+        let trie = trie(&[
+            (
+                HttpMethod::Get,
+                htrpc_entry_point!["users", "me", "avatar"],
+                "avatar",
+            ),
+            (
+                HttpMethod::Get,
+                htrpc_entry_point!["users", _, "posts"],
+                "posts",
+            ),
+        ]);
+        assert_eq!(
+            trie.lookup(&["users", "me", "avatar"], HttpMethod::Get)
+                .ok(),
+            Some(&"avatar")
+        );
+        assert_eq!(
+            trie.lookup(&["users", "me", "posts"], HttpMethod::Get).ok(),
+            Some(&"posts")
+        );
+        assert_eq!(
+            trie.lookup(&["users", "foo", "posts"], HttpMethod::Get)
+                .ok(),
+            Some(&"posts")
+        );
+        assert!(trie
+            .lookup(&["users", "foo", "avatar"], HttpMethod::Get)
+            .is_err());
+        assert!(trie.lookup(&["users", "me"], HttpMethod::Get).is_err());
+    }
+
+    #[test]
+    fn most_specific_match_wins() {
+        let trie = trie(&[
+            (HttpMethod::Get, htrpc_entry_point!["a", _, _], "a__"),
+            (HttpMethod::Get, htrpc_entry_point!["a", _, "c"], "a_c"),
+            (HttpMethod::Get, htrpc_entry_point!["a", "b", _], "ab_"),
+            (HttpMethod::Post, htrpc_entry_point!["a", "b", "c"], "abc"),
+        ]);
+        assert_eq!(
+            trie.lookup(&["a", "b", "c"], HttpMethod::Post).ok(),
+            Some(&"abc")
+        );
+
+        // The leftmost literal segment takes precedence.
+        assert_eq!(
+            trie.lookup(&["a", "b", "c"], HttpMethod::Get).ok(),
+            Some(&"ab_")
+        );
+        assert_eq!(
+            trie.lookup(&["a", "x", "c"], HttpMethod::Get).ok(),
+            Some(&"a_c")
+        );
+        assert_eq!(
+            trie.lookup(&["a", "x", "y"], HttpMethod::Get).ok(),
+            Some(&"a__")
+        );
+
+        match trie.lookup(&["a", "b", "c"], HttpMethod::Put) {
+            Err(RouteError::MethodNotAllowed { allow }) => assert_eq!(
+                allow,
+                [HttpMethod::Get, HttpMethod::Options, HttpMethod::Post]
+            ),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match trie.lookup(&["a", "x", "y"], HttpMethod::Put) {
+            Err(RouteError::MethodNotAllowed { allow }) => {
+                assert_eq!(allow, [HttpMethod::Get, HttpMethod::Options])
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match trie.lookup(&["b"], HttpMethod::Get) {
+            Err(RouteError::NotFound) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn owned_entry_point_works() {
+        use types::PathSegment::*;
+        let prefix = "api".to_owned();
+        let entry_point = EntryPoint::from_segments(vec![Val(prefix.into()), Var]);
+        let trie = trie(&[
+            (HttpMethod::Get, entry_point, "owned"),
+            (HttpMethod::Get, htrpc_entry_point!["api", "v1"], "static"),
+        ]);
+        assert_eq!(
+            trie.lookup(&["api", "v1"], HttpMethod::Get).ok(),
+            Some(&"static")
+        );
+        assert_eq!(
+            trie.lookup(&["api", "v2"], HttpMethod::Get).ok(),
+            Some(&"owned")
+        );
+    }
+
+    #[test]
+    fn tail_segment_works() {
+        let trie = trie(&[
+            (HttpMethod::Get, htrpc_entry_point!["objects", *], "tail"),
+            (HttpMethod::Get, htrpc_entry_point!["objects", _], "var"),
+            (
+                HttpMethod::Get,
+                htrpc_entry_point!["objects", "a", "b"],
+                "ab",
+            ),
+        ]);
+        assert_eq!(
+            trie.lookup(&["objects"], HttpMethod::Get).ok(),
+            Some(&"tail")
+        );
+        assert_eq!(
+            trie.lookup(&["objects", "a"], HttpMethod::Get).ok(),
+            Some(&"var")
+        );
+        assert_eq!(
+            trie.lookup(&["objects", "a", "b"], HttpMethod::Get).ok(),
+            Some(&"ab")
+        );
+        assert_eq!(
+            trie.lookup(&["objects", "a", "c"], HttpMethod::Get).ok(),
+            Some(&"tail")
+        );
+        assert_eq!(
+            trie.lookup(&["objects", "a", "b", "c"], HttpMethod::Get)
+                .ok(),
+            Some(&"tail")
+        );
+        assert!(trie.lookup(&["foo"], HttpMethod::Get).is_err());
+
+        static INVALID: &[::types::PathSegment] =
+            &[::types::PathSegment::Tail, ::types::PathSegment::Var];
+        let mut trie = Trie::new();
+        assert!(trie
+            .insert(HttpMethod::Get, &EntryPoint::new(INVALID), "invalid")
+            .is_err());
+    }
 }
@@ -1,5 +1,6 @@
 use fibers::net::TcpStream;
 use miasht::builtin::headers;
+use miasht::header::HeadersMut;
 use miasht::server::{Connection, Response, ResponseBuilder};
 use miasht::status::RawStatus;
 use serde::{ser, Serialize};
@@ -16,6 +17,7 @@ use types::HttpStatus;
 pub struct RpcResponseSerializer {
     connection: Option<Connection<TcpStream>>,
     response: Option<ResponseBuilder<TcpStream>>,
+    status: u16,
 }
 impl RpcResponseSerializer {
     /// Serializes the RPC response.
@@ -37,6 +39,7 @@ impl RpcResponseSerializer {
         RpcResponseSerializer {
             connection: Some(connection),
             response: None,
+            status: 0,
         }
     }
 
@@ -45,8 +48,21 @@ impl RpcResponseSerializer {
         self,
         body: Box<dyn AsRef<[u8]> + Send + 'static>,
     ) -> Result<(Response<TcpStream>, Box<dyn AsRef<[u8]> + Send + 'static>)> {
+        track!(self.finish_with(body, |_, _, _| ()))
+    }
+
+    /// Finishes the serialization after calling `f` with the status code, body and headers.
+    pub(crate) fn finish_with<F>(
+        self,
+        body: Box<dyn AsRef<[u8]> + Send + 'static>,
+        f: F,
+    ) -> Result<(Response<TcpStream>, Box<dyn AsRef<[u8]> + Send + 'static>)>
+    where
+        F: FnOnce(u16, &[u8], HeadersMut),
+    {
         track_assert!(self.response.is_some(), ErrorKind::Invalid);
         let mut response = self.response.expect("Never fail");
+        f(self.status, (*body).as_ref(), response.headers_mut());
         response.add_header(&headers::ContentLength((*body).as_ref().len() as u64));
         Ok((response.finish(), body))
     }
@@ -128,6 +144,7 @@ impl<'a> ser::Serializer for &'a mut RpcResponseSerializer {
     ) -> Result<Self::Ok> {
         track_assert!(self.connection.is_some(), ErrorKind::Invalid);
         let status = track!(status_from_str(variant))?;
+        self.status = status.code();
         let response = self.connection.take().unwrap().build_response(status);
         self.response = Some(response);
         Ok(())
@@ -187,6 +204,7 @@ impl<'a> ser::Serializer for &'a mut RpcResponseSerializer {
     ) -> Result<Self::SerializeStructVariant> {
         track_assert!(self.connection.is_some(), ErrorKind::Invalid);
         let status = track!(status_from_str(variant))?;
+        self.status = status.code();
         let response = self.connection.take().unwrap().build_response(status);
         self.response = Some(response);
         Ok(self)
@@ -211,6 +229,7 @@ impl<'a> ser::SerializeStruct for &'a mut RpcResponseSerializer {
                     "Unknown HTTP status: {}",
                     status
                 )?;
+                self.status = status.code();
                 let response = self.connection.take().unwrap().build_response(status);
                 self.response = Some(response);
                 Ok(())
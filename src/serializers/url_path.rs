@@ -4,7 +4,7 @@ use url::{PathSegmentsMut, Url};
 use trackable::error::ErrorKindExt;
 
 use {Error, ErrorKind, Result};
-use types::EntryPoint;
+use types::{EntryPoint, PathSegment};
 
 /// `Serializer` implementation for URL path.
 pub struct UrlPathSerializer<'a> {
@@ -12,6 +12,7 @@ pub struct UrlPathSerializer<'a> {
     entry_point: &'a EntryPoint,
     index: usize,
     is_started: bool,
+    in_tail: bool,
 }
 impl<'a> UrlPathSerializer<'a> {
     /// Makes a new `UrlPathSerializer` instance.
@@ -25,13 +26,25 @@ impl<'a> UrlPathSerializer<'a> {
             entry_point,
             index: 0,
             is_started: false,
+            in_tail: false,
         })
     }
 
     fn bind_next_var(&mut self, value: &str) -> Result<()> {
         track_assert!(self.is_started, ErrorKind::Invalid);
+        if self.in_tail {
+            self.segments.push(value);
+            return Ok(());
+        }
         track_assert!(!self.append_until_next_var(), ErrorKind::Invalid);
-        self.segments.push(value);
+        if self.entry_point.segments()[self.index] == PathSegment::Tail {
+            // NOTE: Empty segments are kept so that `"a//b"` and `"a/"` can be round-tripped.
+            if !value.is_empty() {
+                self.segments.extend(value.split('/'));
+            }
+        } else {
+            self.segments.push(value);
+        }
         self.index += 1;
         Ok(())
     }
@@ -59,7 +72,7 @@ impl<'a, 'b> ser::Serializer for &'a mut UrlPathSerializer<'b> {
     type Ok = ();
     type Error = Error;
 
-    type SerializeSeq = Impossible<Self::Ok, Self::Error>;
+    type SerializeSeq = Self;
     type SerializeTuple = Self;
     type SerializeTupleStruct = Self;
     type SerializeTupleVariant = Self;
@@ -161,7 +174,16 @@ impl<'a, 'b> ser::Serializer for &'a mut UrlPathSerializer<'b> {
         track!(value.serialize(self))
     }
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        track_panic!(ErrorKind::Invalid);
+        track_assert!(self.is_started, ErrorKind::Invalid);
+        track_assert!(!self.in_tail, ErrorKind::Invalid);
+        track_assert!(!self.append_until_next_var(), ErrorKind::Invalid);
+        track_assert_eq!(
+            self.entry_point.segments()[self.index],
+            PathSegment::Tail,
+            ErrorKind::Invalid
+        );
+        self.in_tail = true;
+        Ok(self)
     }
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
         track_assert!(!self.is_started, ErrorKind::Invalid);
@@ -207,6 +229,22 @@ impl<'a, 'b> ser::Serializer for &'a mut UrlPathSerializer<'b> {
         track_panic!(ErrorKind::Invalid);
     }
 }
+impl<'a, 'b> ser::SerializeSeq for &'a mut UrlPathSerializer<'b> {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        track!(value.serialize(&mut **self))?;
+        Ok(())
+    }
+    fn end(self) -> Result<Self::Ok> {
+        self.in_tail = false;
+        self.index += 1;
+        Ok(())
+    }
+}
 impl<'a, 'b> ser::SerializeTuple for &'a mut UrlPathSerializer<'b> {
     type Ok = ();
     type Error = Error;
@@ -255,7 +293,9 @@ impl<'a, 'b> ser::SerializeTupleVariant for &'a mut UrlPathSerializer<'b> {
 
 #[cfg(test)]
 mod test {
+    use serde::Deserialize;
     use url::Url;
+    use deserializers::UrlPathDeserializer;
     use super::*;
 
     #[test]
@@ -272,4 +312,60 @@ mod test {
         }
         assert_eq!(url.as_str(), "http://localhost/foo/hello%20world/baz/3");
     }
+
+    #[test]
+    fn tail_works() {
+        let entry_point = htrpc_entry_point!["objects", _, *];
+
+        #[derive(Serialize)]
+        struct Joined(&'static str, &'static str);
+
+        #[derive(Serialize)]
+        struct Segments(&'static str, Vec<&'static str>);
+
+        let mut url = Url::parse("http://localhost/").unwrap();
+        {
+            let mut serializer = track_try_unwrap!(UrlPathSerializer::new(&entry_point, &mut url));
+            track_try_unwrap!(Joined("foo", "a/b c/d").serialize(&mut serializer));
+        }
+        assert_eq!(url.as_str(), "http://localhost/objects/foo/a/b%20c/d");
+
+        let mut url = Url::parse("http://localhost/").unwrap();
+        {
+            let mut serializer = track_try_unwrap!(UrlPathSerializer::new(&entry_point, &mut url));
+            track_try_unwrap!(Segments("foo", vec!["a", "b c", "d"]).serialize(&mut serializer));
+        }
+        assert_eq!(url.as_str(), "http://localhost/objects/foo/a/b%20c/d");
+
+        let mut url = Url::parse("http://localhost/").unwrap();
+        {
+            let mut serializer = track_try_unwrap!(UrlPathSerializer::new(&entry_point, &mut url));
+            track_try_unwrap!(Segments("foo", vec![]).serialize(&mut serializer));
+        }
+        assert_eq!(url.as_str(), "http://localhost/objects/foo");
+    }
+
+    #[test]
+    fn tail_round_trip_works() {
+        let entry_point = htrpc_entry_point!["objects", _, *];
+
+        #[derive(Serialize, Deserialize)]
+        struct Joined(String, String);
+
+        for tail in &["", "a", "a//b", "a/", "/a", "a/b c/d"] {
+            let mut url = Url::parse("http://localhost/").unwrap();
+            {
+                let mut serializer =
+                    track_try_unwrap!(UrlPathSerializer::new(&entry_point, &mut url));
+                let args = Joined("foo".to_owned(), tail.to_string());
+                track_try_unwrap!(args.serialize(&mut serializer));
+            }
+
+            let mut deserializer =
+                track_try_unwrap!(UrlPathDeserializer::new(entry_point.clone(), &url));
+            let Joined(v0, v1) = track_try_unwrap!(Joined::deserialize(&mut deserializer));
+            assert_eq!(v0, "foo");
+            assert_eq!(v1, *tail, "url={}", url);
+        }
+    }
 }
@@ -1,26 +1,43 @@
+use fibers::net::futures::Connected;
 use fibers::net::TcpStream;
+use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer::{self, TimeoutAfter, TimerExt};
 use fibers::{self, BoxSpawn, Spawn};
+use futures::future::{Either, MapErr};
 use futures::stream::StreamFuture;
 use futures::{self, Async, Future, Poll, Stream};
 use handy_async::future::Phase;
+use httparse;
 use miasht;
 use miasht::builtin::futures::FutureExt;
-use miasht::builtin::io::IoExt;
 use miasht::server::{Connection, Request, Response};
 use serde::Deserialize;
+use serdeconv;
 use slog::{Discard, Logger};
+use std::any::Any;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
+use body::{BodyReader, PayloadTooLarge, ReadBodyTimeout};
 use deserializers::RpcRequestDeserializer;
+use middleware::{Middleware, MiddlewareChain, RawResponse};
 use misc;
-use procedure::{HandleRpc, Procedure};
-use rfc7807::Problem;
-use router::{Router, RouterBuilder};
+use procedure::{
+    HandleFallibleRpc, HandleRpc, HandleRpcWithContext, IntoErrorResponse, NeverFail, Procedure,
+};
+use rfc7807::{AboutBlankProblem, Problem};
+use router::{RouteError, Router, RouterBuilder};
 use serializers::RpcResponseSerializer;
-use types::{HttpMethod, HttpStatus};
-use {Error, ErrorKind, Result};
+use types::{EntryPoint, HttpMethod, HttpStatus};
+use {Error, ErrorKind, RequestContext, Result, RpcResponse};
 
 type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send + 'static>;
 
@@ -29,6 +46,21 @@ pub struct RpcServerBuilder {
     bind_addr: SocketAddr,
     logger: Logger,
     router: RouterBuilder,
+    shutdown_grace_period: Duration,
+    debug_mode: bool,
+    initial_buffer_size: usize,
+    max_header_bytes: usize,
+    max_header_count: usize,
+    max_uri_length: usize,
+    max_body_size: Option<u64>,
+    read_header_timeout: Option<Duration>,
+    read_body_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    handler_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    max_in_flight_requests: Option<usize>,
+    retry_after: Duration,
+    middlewares: Vec<Arc<dyn Middleware>>,
 }
 impl RpcServerBuilder {
     /// Makes a new `RpcServerBuilder` instance.
@@ -37,6 +69,21 @@ impl RpcServerBuilder {
             bind_addr,
             logger: Logger::root(Discard, o!()),
             router: RouterBuilder::new(),
+            shutdown_grace_period: Duration::from_secs(30),
+            debug_mode: false,
+            initial_buffer_size: 1024,
+            max_header_bytes: 8096,
+            max_header_count: 32,
+            max_uri_length: 4096,
+            max_body_size: None,
+            read_header_timeout: None,
+            read_body_timeout: None,
+            keep_alive_timeout: None,
+            handler_timeout: None,
+            max_connections: None,
+            max_in_flight_requests: None,
+            retry_after: Duration::from_secs(1),
+            middlewares: Vec::new(),
         }
     }
 
@@ -45,55 +92,371 @@ impl RpcServerBuilder {
         self.logger = logger;
     }
 
+    /// Sets the maximum time to wait for in-flight requests when the server is shutting down.
+    ///
+    /// Connections which are still active after this period are forcibly closed.
+    ///
+    /// The default value is 30 seconds.
+    pub fn set_shutdown_grace_period(&mut self, period: Duration) {
+        self.shutdown_grace_period = period;
+    }
+
+    /// Sets whether this server is running in debug mode.
+    ///
+    /// In debug mode, the error responses for internal failures (e.g., panics of handlers)
+    /// contain the detailed information of the failures.
+    ///
+    /// The default value is `false`.
+    pub fn set_debug_mode(&mut self, enabled: bool) {
+        self.debug_mode = enabled;
+    }
+
+    /// Sets the initial size of the per connection buffer
+    /// which is used for reading request heads and writing response heads.
+    ///
+    /// The buffer is expanded as needed up to the size specified by `set_max_header_bytes`.
+    ///
+    /// The default value is `1024`.
+    pub fn set_initial_buffer_size(&mut self, size: usize) {
+        self.initial_buffer_size = size;
+    }
+
+    /// Sets the maximum number of bytes of a request line and headers.
+    ///
+    /// If a request exceeds this limit,
+    /// the server will respond with `431 Request Header Fields Too Large`
+    /// and close the connection.
+    /// Note that this value also limits the size of response heads.
+    ///
+    /// The default value is `8096`.
+    pub fn set_max_header_bytes(&mut self, size: usize) {
+        self.max_header_bytes = size;
+    }
+
+    /// Sets the maximum number of headers in a request.
+    ///
+    /// If a request exceeds this limit,
+    /// the server will respond with `431 Request Header Fields Too Large`
+    /// and close the connection.
+    ///
+    /// The default value is `32`.
+    pub fn set_max_header_count(&mut self, count: usize) {
+        self.max_header_count = count;
+    }
+
+    /// Sets the maximum length of a request URI.
+    ///
+    /// If a request exceeds this limit, the server will respond with `414 URI Too Long`.
+    ///
+    /// This should be smaller than the value of `set_max_header_bytes`,
+    /// because a request line which does not fit in the buffer for request heads
+    /// is rejected with `431 Request Header Fields Too Large` before its URI is inspected.
+    ///
+    /// The default value is `4096`.
+    pub fn set_max_uri_length(&mut self, length: usize) {
+        self.max_uri_length = length;
+    }
+
+    /// Sets the maximum number of bytes of a request body.
+    ///
+    /// If a request exceeds this limit,
+    /// the server will respond with `413 Payload Too Large` and close the connection.
+    /// The limit can be overridden for each procedure by using `ProcedureOptions`.
+    ///
+    /// By default, the size of request bodies is unlimited.
+    pub fn set_max_body_size(&mut self, size: u64) {
+        self.max_body_size = Some(size);
+    }
+
+    /// Sets the timeout for reading the head (i.e., request line and headers) of a request.
+    ///
+    /// On a keep-alive connection, this timeout starts when the first byte of the next request
+    /// arrives (see also `set_keep_alive_timeout`).
+    ///
+    /// If the timeout expires, the server will respond with `408 Request Timeout`
+    /// and close the connection.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_read_header_timeout(&mut self, timeout: Duration) {
+        self.read_header_timeout = Some(timeout);
+    }
+
+    /// Sets the timeout for reading a request body.
+    ///
+    /// If the timeout expires, the server will respond with `408 Request Timeout`
+    /// and close the connection.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_read_body_timeout(&mut self, timeout: Duration) {
+        self.read_body_timeout = Some(timeout);
+    }
+
+    /// Sets the timeout for waiting for the next request on a keep-alive connection.
+    ///
+    /// Once the first byte of the next request arrives,
+    /// the rest of its head is read within the timeout set by `set_read_header_timeout`.
+    ///
+    /// If the timeout expires, the connection is closed silently.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_keep_alive_timeout(&mut self, timeout: Duration) {
+        self.keep_alive_timeout = Some(timeout);
+    }
+
+    /// Sets the timeout for RPC handlers.
+    ///
+    /// If a handler does not complete within the timeout,
+    /// the server will respond with `503 Service Unavailable`.
+    /// The timeout can be overridden for each procedure by using `ProcedureOptions`.
+    ///
+    /// By default, there is no timeout.
+    pub fn set_handler_timeout(&mut self, timeout: Duration) {
+        self.handler_timeout = Some(timeout);
+    }
+
+    /// Sets the maximum number of concurrent connections.
+    ///
+    /// If the number of connections reaches this limit,
+    /// the server stops accepting new connections until some of the existing ones are closed.
+    ///
+    /// By default, the number of connections is unlimited.
+    pub fn set_max_connections(&mut self, count: usize) {
+        self.max_connections = Some(count);
+    }
+
+    /// Sets the maximum number of requests which are concurrently handled by RPC handlers.
+    ///
+    /// If the number of in-flight requests reaches this limit,
+    /// the server will respond to new requests with `503 Service Unavailable`
+    /// (see also `set_retry_after`).
+    ///
+    /// By default, the number of in-flight requests is unlimited.
+    pub fn set_max_in_flight_requests(&mut self, count: usize) {
+        self.max_in_flight_requests = Some(count);
+    }
+
+    /// Sets the value of the `Retry-After` header of `503 Service Unavailable` responses
+    /// which are returned when the server is overloaded.
+    ///
+    /// The default value is 1 second.
+    pub fn set_retry_after(&mut self, delay: Duration) {
+        self.retry_after = delay;
+    }
+
+    /// Adds a middleware which is applied to all the procedures registered to this server.
+    ///
+    /// See the documentation of the `middleware` module for the order of invocations
+    /// and the responses which bypass middlewares (e.g., `404 Not Found`).
+    pub fn add_middleware<M: Middleware>(&mut self, middleware: M) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
     /// Registers an RPC handler.
-    pub fn register<P, H>(&mut self, handler: H, _: P) -> Result<()>
+    pub fn register<P, H>(&mut self, handler: H, procedure: P) -> Result<()>
     where
         P: Procedure,
         H: HandleRpc<P>,
+    {
+        track!(self.register_with_options(handler, procedure, ProcedureOptions::default()))
+    }
+
+    /// Registers an RPC handler with the procedure specific options.
+    pub fn register_with_options<P, H>(
+        &mut self,
+        handler: H,
+        procedure: P,
+        options: ProcedureOptions,
+    ) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleRpc<P>,
+    {
+        track!(self.register_fallible_with_options(InfallibleHandler(handler), procedure, options))
+    }
+
+    /// Registers a fallible RPC handler.
+    ///
+    /// The errors returned by the handler are converted into RPC responses
+    /// by using the `IntoErrorResponse` trait.
+    pub fn register_fallible<P, H>(&mut self, handler: H, procedure: P) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleFallibleRpc<P>,
+    {
+        track!(self.register_fallible_with_options(handler, procedure, ProcedureOptions::default()))
+    }
+
+    /// Registers a fallible RPC handler with the procedure specific options.
+    pub fn register_fallible_with_options<P, H>(
+        &mut self,
+        handler: H,
+        procedure: P,
+        options: ProcedureOptions,
+    ) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleFallibleRpc<P>,
+    {
+        track!(self.register_handler(WithoutContext(handler), procedure, options))
+    }
+
+    /// Registers an RPC handler which takes the contexts of requests.
+    pub fn register_with_context<P, H>(&mut self, handler: H, procedure: P) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleRpcWithContext<P>,
+    {
+        track!(self.register_with_context_and_options(
+            handler,
+            procedure,
+            ProcedureOptions::default()
+        ))
+    }
+
+    /// Registers an RPC handler which takes the contexts of requests
+    /// with the procedure specific options.
+    pub fn register_with_context_and_options<P, H>(
+        &mut self,
+        handler: H,
+        procedure: P,
+        options: ProcedureOptions,
+    ) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleRpcWithContext<P>,
+    {
+        track!(self.register_handler(WithContext(handler), procedure, options))
+    }
+
+    fn register_handler<P, H>(
+        &mut self,
+        handler: H,
+        _: P,
+        procedure_options: ProcedureOptions,
+    ) -> Result<()>
+    where
+        P: Procedure,
+        H: HandleRpcInternal<P>,
     {
         use RpcRequest;
-        let handle_http_request = move |url, http_request| {
+        let handle_http_request = move |entry_point: &EntryPoint,
+                                        url,
+                                        http_request,
+                                        options: &ServerOptions,
+                                        peer_addr| {
             let handler = handler.clone();
+            let options = options.clone();
+            let max_body_size = procedure_options.max_body_size.or(options.max_body_size);
+            let handler_timeout = procedure_options
+                .handler_timeout
+                .or(options.handler_timeout);
+            // NOTE: The context is built only if someone uses it,
+            // because it copies the URL and the headers of the request.
+            let mut middlewares = if H::NEEDS_CONTEXT
+                || !options.middlewares.is_empty()
+                || !procedure_options.middlewares.is_empty()
+            {
+                let context =
+                    RequestContext::new(peer_addr, entry_point.clone(), &url, &http_request);
+                MiddlewareChain::new(
+                    &options.middlewares,
+                    &procedure_options.middlewares,
+                    context,
+                )
+            } else {
+                MiddlewareChain::default()
+            };
+            if let Some(response) = middlewares.before_handle() {
+                return discard_body_and_respond(
+                    http_request,
+                    max_body_size,
+                    options.read_body_timeout,
+                    response,
+                    middlewares,
+                );
+            }
             let rpc_request: P::Request = {
                 let deserialize_result = {
-                    let mut de = RpcRequestDeserializer::new(P::entry_point(), &url, &http_request);
+                    let mut de =
+                        RpcRequestDeserializer::new(entry_point.clone(), &url, &http_request);
                     track!(Deserialize::deserialize(&mut de))
                 };
                 match deserialize_result {
                     Err(e) => {
-                        let future = futures::done(http_request.into_body_reader())
-                            .map_err(Error::from)
-                            .and_then(|request| request.read_all_bytes().map_err(Error::from))
-                            .and_then(move |(request, _)| {
-                                let http_request = request.into_inner();
-                                let rpc_response =
-                                    Problem::trackable(HttpStatus::BadRequest, e).into_response();
-                                track!(RpcResponseSerializer::serialize(
-                                    rpc_response,
-                                    http_request.finish(),
-                                ))
-                            });
-                        let future: BoxFuture<_, _> = Box::new(future);
-                        return future;
+                        let problem = Problem::trackable(HttpStatus::BadRequest, e);
+                        return discard_body_and_respond(
+                            http_request,
+                            max_body_size,
+                            options.read_body_timeout,
+                            problem.into_response(),
+                            middlewares,
+                        );
                     }
                     Ok(r) => r,
                 }
             };
-            let future = futures::done(http_request.into_body_reader())
-                .map_err(Error::from)
-                .and_then(move |http_request| rpc_request.read_body(http_request))
-                .and_then(move |(http_request, rpc_request)| {
+            let entry_point = entry_point.clone();
+            let read_body = futures::done(BodyReader::new(http_request, max_body_size))
+                .and_then(move |http_request| rpc_request.read_body(http_request));
+            let future = with_read_body_timeout(read_body, options.read_body_timeout).and_then(
+                move |(http_request, rpc_request)| {
                     let http_request = http_request.into_inner();
-                    handler
-                        .handle_rpc(rpc_request)
-                        .map_err(|_| unreachable!())
-                        .and_then(move |rpc_response| {
-                            track!(RpcResponseSerializer::serialize(
-                                rpc_response,
-                                http_request.finish(),
-                            ))
-                        })
-                });
+                    let context = middlewares.context();
+                    let result = panic::catch_unwind(AssertUnwindSafe(move || {
+                        handler.handle_rpc(context, rpc_request)
+                    }));
+                    match result {
+                        Err(panic) => Either::A(futures::done(track!(panic_response::<P>(
+                            &*panic,
+                            &entry_point,
+                            &options,
+                            http_request.finish(),
+                            &middlewares,
+                        )))),
+                        Ok(future) => Either::B(
+                            with_timeout(AssertUnwindSafe(future).catch_unwind(), handler_timeout)
+                                .then(move |result| match result {
+                                    Ok(Ok(rpc_response)) => track!(serialize_response(
+                                        rpc_response,
+                                        http_request.finish(),
+                                        &middlewares,
+                                    )),
+                                    Ok(Err(e)) => track!(serialize_response(
+                                        if options.debug_mode {
+                                            e.into_debug_error_response()
+                                        } else {
+                                            e.into_error_response()
+                                        },
+                                        http_request.finish(),
+                                        &middlewares,
+                                    )),
+                                    Err(Some(panic)) => track!(panic_response::<P>(
+                                        &*panic,
+                                        &entry_point,
+                                        &options,
+                                        http_request.finish(),
+                                        &middlewares,
+                                    )),
+                                    Err(None) => {
+                                        warn!(
+                                            options.logger,
+                                            "RPC handler timed out: method={}, entry_point={:?}",
+                                            P::method(),
+                                            entry_point
+                                        );
+                                        let problem =
+                                            Problem::about_blank(HttpStatus::ServiceUnavailable);
+                                        track!(serialize_response(
+                                            problem.into_response(),
+                                            http_request.finish(),
+                                            &middlewares,
+                                        ))
+                                    }
+                                }),
+                        ),
+                    }
+                },
+            );
             let future: BoxFuture<_, _> = Box::new(future);
             future
         };
@@ -103,33 +466,271 @@ impl RpcServerBuilder {
         Ok(())
     }
 
+    /// Mounts the procedures registered to `procedures` under `prefix`.
+    ///
+    /// For example, if `prefix` is `htrpc_entry_point!["v1", "billing"]`,
+    /// a procedure of which entry point is `["invoices", _]` is served at `/v1/billing/invoices/_`.
+    /// `prefix` must consist of only literal segments.
+    ///
+    /// Note that the settings other than the procedures (e.g., timeouts and global middlewares)
+    /// of `procedures` are ignored. The settings of this builder are applied to the mounted procedures.
+    pub fn mount(&mut self, prefix: &EntryPoint, procedures: RpcServerBuilder) -> Result<()> {
+        track!(self.router.mount(prefix, procedures.router))
+    }
+
     /// Starts the `Future` which represents the RPC server.
     pub fn start<S>(self, spawner: S) -> RpcServer
     where
         S: Spawn + Send + 'static,
     {
+        let (command_tx, command_rx) = mpsc::channel();
+        let options = ServerOptions {
+            logger: self.logger.clone(),
+            debug_mode: self.debug_mode,
+            initial_buffer_size: cmp::min(self.initial_buffer_size, self.max_header_bytes),
+            max_header_bytes: self.max_header_bytes,
+            max_header_count: self.max_header_count,
+            max_uri_length: self.max_uri_length,
+            max_body_size: self.max_body_size,
+            read_header_timeout: self.read_header_timeout,
+            read_body_timeout: self.read_body_timeout,
+            keep_alive_timeout: self.keep_alive_timeout,
+            handler_timeout: self.handler_timeout,
+            max_in_flight_requests: self.max_in_flight_requests,
+            in_flight_requests: Arc::new(AtomicUsize::new(0)),
+            retry_after: self.retry_after,
+            middlewares: Arc::new(self.middlewares),
+        };
         RpcServer {
             spawner: spawner.boxed(),
             logger: self.logger,
+            options,
             router: self.router.finish(),
             phase: Phase::A(fibers::net::TcpListener::bind(self.bind_addr)),
+            command_tx,
+            command_rx,
+            local_addr: Arc::new(Mutex::new(None)),
+            connections: HashMap::new(),
+            seq_no: 0,
+            shutdown_grace_period: self.shutdown_grace_period,
+            max_connections: self.max_connections,
         }
     }
 }
 
+/// Options shared by the connections and the HTTP request handlers of a server.
+#[derive(Clone)]
+pub(crate) struct ServerOptions {
+    logger: Logger,
+    debug_mode: bool,
+    initial_buffer_size: usize,
+    max_header_bytes: usize,
+    max_header_count: usize,
+    max_uri_length: usize,
+    max_body_size: Option<u64>,
+    read_header_timeout: Option<Duration>,
+    read_body_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    handler_timeout: Option<Duration>,
+    max_in_flight_requests: Option<usize>,
+    in_flight_requests: Arc<AtomicUsize>,
+    retry_after: Duration,
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+}
+
+/// Options for a procedure registered to `RpcServerBuilder`.
+///
+/// The options which are not specified inherit the server-wide settings.
+#[derive(Clone, Default)]
+pub struct ProcedureOptions {
+    max_body_size: Option<u64>,
+    handler_timeout: Option<Duration>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+impl ProcedureOptions {
+    /// Makes a new `ProcedureOptions` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of bytes of a request body for the procedure.
+    ///
+    /// See also `RpcServerBuilder::set_max_body_size`.
+    pub fn set_max_body_size(&mut self, size: u64) {
+        self.max_body_size = Some(size);
+    }
+
+    /// Sets the timeout for the handler of the procedure.
+    ///
+    /// See also `RpcServerBuilder::set_handler_timeout`.
+    pub fn set_handler_timeout(&mut self, timeout: Duration) {
+        self.handler_timeout = Some(timeout);
+    }
+
+    /// Adds a middleware which is applied to the procedure.
+    ///
+    /// See the documentation of the `middleware` module for the order of invocations.
+    pub fn add_middleware<M: Middleware>(&mut self, middleware: M) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+}
+impl fmt::Debug for ProcedureOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProcedureOptions")
+            .field("max_body_size", &self.max_body_size)
+            .field("handler_timeout", &self.handler_timeout)
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
+
+/// The handler of a registered procedure.
+///
+/// This tells whether the handler needs the contexts of requests,
+/// so that the server can skip building them.
+trait HandleRpcInternal<P: Procedure>: Clone + Send + Sync + 'static {
+    type Error: IntoErrorResponse;
+    type Future: Future<Item = P::Response, Error = Self::Error> + Send + 'static;
+
+    /// If `false`, `handle_rpc` may be called with `None`.
+    const NEEDS_CONTEXT: bool;
+
+    fn handle_rpc(self, context: Option<&RequestContext>, request: P::Request) -> Self::Future;
+}
+
+#[derive(Clone)]
+struct WithContext<H>(H);
+impl<P, H> HandleRpcInternal<P> for WithContext<H>
+where
+    P: Procedure,
+    H: HandleRpcWithContext<P>,
+{
+    type Error = H::Error;
+    type Future = H::Future;
+    const NEEDS_CONTEXT: bool = true;
+    fn handle_rpc(self, context: Option<&RequestContext>, request: P::Request) -> Self::Future {
+        let context = context.expect("Never fails");
+        self.0.handle_rpc(context, request)
+    }
+}
+
+#[derive(Clone)]
+struct WithoutContext<H>(H);
+impl<P, H> HandleRpcInternal<P> for WithoutContext<H>
+where
+    P: Procedure,
+    H: HandleFallibleRpc<P>,
+{
+    type Error = H::Error;
+    type Future = H::Future;
+    const NEEDS_CONTEXT: bool = false;
+    fn handle_rpc(self, _context: Option<&RequestContext>, request: P::Request) -> Self::Future {
+        self.0.handle_rpc(request)
+    }
+}
+
+#[derive(Clone)]
+struct InfallibleHandler<H>(H);
+impl<P, H> HandleFallibleRpc<P> for InfallibleHandler<H>
+where
+    P: Procedure,
+    H: HandleRpc<P>,
+{
+    type Error = NeverFail;
+    type Future = H::Future;
+    fn handle_rpc(self, request: P::Request) -> Self::Future {
+        self.0.handle_rpc(request)
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Shutdown,
+    ConnectionClosed { id: u64 },
+}
+
+struct ConnectionHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    _link: oneshot::Link<(), (), (), ()>,
+}
+
 /// RPC Server.
 pub struct RpcServer {
     spawner: BoxSpawn,
     logger: Logger,
+    options: ServerOptions,
     router: Router,
-    phase:
-        Phase<fibers::net::futures::TcpListenerBind, StreamFuture<fibers::net::streams::Incoming>>,
+    phase: Phase<
+        fibers::net::futures::TcpListenerBind,
+        StreamFuture<fibers::net::streams::Incoming>,
+        timer::Timeout,
+    >,
+    command_tx: mpsc::Sender<Command>,
+    command_rx: mpsc::Receiver<Command>,
+    local_addr: Arc<Mutex<Option<SocketAddr>>>,
+    connections: HashMap<u64, ConnectionHandle>,
+    seq_no: u64,
+    shutdown_grace_period: Duration,
+    max_connections: Option<usize>,
+}
+impl RpcServer {
+    /// Returns a handle of this server.
+    pub fn handle(&self) -> RpcServerHandle {
+        RpcServerHandle {
+            command_tx: self.command_tx.clone(),
+            local_addr: Arc::clone(&self.local_addr),
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        matches!(self.phase, Phase::C(_))
+    }
+    fn is_accept_paused(&self) -> bool {
+        matches!(self.phase, Phase::B(_))
+            && self
+                .max_connections
+                .is_some_and(|max| self.connections.len() >= max)
+    }
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Shutdown => {
+                if self.is_shutting_down() {
+                    return;
+                }
+                info!(
+                    self.logger,
+                    "RPC server is shutting down: connections={}",
+                    self.connections.len()
+                );
+                for connection in self.connections.values_mut() {
+                    if let Some(shutdown_tx) = connection.shutdown_tx.take() {
+                        let _ = shutdown_tx.send(());
+                    }
+                }
+                self.phase = Phase::C(timer::timeout(self.shutdown_grace_period));
+            }
+            Command::ConnectionClosed { id } => {
+                self.connections.remove(&id);
+            }
+        }
+    }
 }
 impl Future for RpcServer {
     type Item = ();
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(command) = self.command_rx.poll().expect("Never fails") {
+            self.handle_command(command.expect("Never fails"));
+        }
+        if self.is_shutting_down() && self.connections.is_empty() {
+            info!(self.logger, "RPC server stopped");
+            return Ok(Async::Ready(()));
+        }
         loop {
+            if self.is_accept_paused() {
+                // NOTE: This task will be notified by `Command::ConnectionClosed`.
+                return Ok(Async::NotReady);
+            }
             let next = match track!(self.phase.poll().map_err(Error::from))? {
                 Async::NotReady => return Ok(Async::NotReady),
                 Async::Ready(Phase::A(listener)) => {
@@ -138,6 +739,11 @@ impl Future for RpcServer {
                         "RPC server started: {:?}",
                         listener.local_addr()
                     );
+                    if let (Ok(addr), Ok(mut local_addr)) =
+                        (listener.local_addr(), self.local_addr.lock())
+                    {
+                        *local_addr = Some(addr);
+                    }
                     Phase::B(listener.incoming().into_future())
                 }
                 Async::Ready(Phase::B((client, incoming))) => {
@@ -145,20 +751,41 @@ impl Future for RpcServer {
                         track!(client.ok_or_else(|| ErrorKind::Invalid.error()))?;
                     debug!(self.logger, "New client is connected: {}", addr);
 
-                    let connected = connected.then(|result| {
-                        let stream = track!(result.map_err(miasht::Error::from))?;
-                        let _ = stream.with_inner(|inner| inner.set_nodelay(true));
-                        Ok(Connection::new(stream, 1024, 8096, 32))
-                    });
+                    let id = self.seq_no;
+                    self.seq_no += 1;
+                    let (shutdown_tx, shutdown_rx) = oneshot::channel();
                     let future = HandleHttpRequest {
-                        logger: self.logger.clone(),
+                        options: self.options.clone(),
                         router: self.router.clone(),
-                        phase: Phase::A(Box::new(connected)),
+                        phase: Phase::D(connected),
                         method: HttpMethod::Get, // Dummy
+                        stream: None,
+                        id,
+                        command_tx: self.command_tx.clone(),
+                        shutdown_rx,
+                        is_shutting_down: false,
+                        in_flight: None,
+                        peer_addr: addr,
                     };
-                    self.spawner.spawn(future);
+                    let link = self.spawner.spawn_link(future);
+                    self.connections.insert(
+                        id,
+                        ConnectionHandle {
+                            shutdown_tx: Some(shutdown_tx),
+                            _link: link,
+                        },
+                    );
                     Phase::B(incoming.into_future())
                 }
+                Async::Ready(Phase::C(())) => {
+                    warn!(
+                        self.logger,
+                        "Shutdown grace period expired: {} connections are forcibly closed",
+                        self.connections.len()
+                    );
+                    self.connections.clear();
+                    return Ok(Async::Ready(()));
+                }
                 _ => unreachable!(),
             };
             self.phase = next;
@@ -167,41 +794,88 @@ impl Future for RpcServer {
 }
 
 struct HandleHttpRequest {
-    logger: Logger,
+    options: ServerOptions,
     router: Router,
-    phase: Phase<
-        BoxFuture<Connection<TcpStream>, miasht::Error>,
-        BoxFuture<Option<Request<TcpStream>>, miasht::Error>,
-        BoxFuture<(Response<TcpStream>, Box<dyn AsRef<[u8]> + Send + 'static>), Error>,
-    >,
+    phase: ConnectionPhase,
     method: HttpMethod,
+    stream: Option<TcpStream>,
+    id: u64,
+    command_tx: mpsc::Sender<Command>,
+    shutdown_rx: oneshot::Receiver<()>,
+    is_shutting_down: bool,
+    in_flight: Option<InFlightRequest>,
+    peer_addr: SocketAddr,
 }
 impl HandleHttpRequest {
     fn poll_impl(&mut self) -> Poll<(), Error> {
+        if !self.is_shutting_down {
+            // NOTE: The disconnection of the channel is also regarded as a shutdown request.
+            self.is_shutting_down = !matches!(self.shutdown_rx.poll(), Ok(Async::NotReady));
+        }
+        if self.is_shutting_down && matches!(self.phase, Phase::B(_)) {
+            // The connection is idle (i.e., waiting for the next request).
+            return Ok(Async::Ready(()));
+        }
         loop {
-            let next = match track!(self.phase.poll().map_err(Error::from))? {
+            let polled = match self.phase.poll().map_err(Error::from) {
+                Err(e) => {
+                    let response = if PayloadTooLarge::is_cause_of(&e) {
+                        (413, "Payload Too Large")
+                    } else if ReadBodyTimeout::is_cause_of(&e) {
+                        (408, "Request Timeout")
+                    } else {
+                        return Err(track!(e));
+                    };
+                    // NOTE: The connection can not be reused because the body is partially read.
+                    let stream = track!(self.stream.take().ok_or(e))?;
+                    let bytes = raw_problem_response(response.0, response.1);
+                    let future: BoxFuture<_, _> = Box::new(stream.write_all_bytes(bytes));
+                    self.phase = Phase::E(future);
+                    continue;
+                }
+                Ok(polled) => polled,
+            };
+            let next = match polled {
                 Async::NotReady => return Ok(Async::NotReady),
-                Async::Ready(Phase::A(connection)) => {
-                    let future = connection.read_request().map(Some).or_else(|e| {
-                        if let Some(e) = e.concrete_cause::<io::Error>() {
-                            if e.kind() == io::ErrorKind::UnexpectedEof
-                                || e.kind() == io::ErrorKind::ConnectionReset
-                            {
-                                // The connection is reset by the peer.
-                                return Ok(None);
-                            }
-                        }
-                        Err(e)
-                    });
-                    let future: BoxFuture<_, _> = Box::new(future);
-                    Phase::B(future)
+                Async::Ready(Phase::A(_)) | Async::Ready(Phase::D(_)) if self.is_shutting_down => {
+                    return Ok(Async::Ready(()));
+                }
+                Async::Ready(Phase::A(connection)) => Phase::B(self.read_request(connection, true)),
+                Async::Ready(Phase::B(IncomingRequest::Eof)) => {
+                    return Ok(Async::Ready(()));
                 }
-                Async::Ready(Phase::B(None)) => {
+                Async::Ready(Phase::B(IncomingRequest::IdleTimeout)) => {
+                    debug!(self.options.logger, "Keep-alive connection timed out");
                     return Ok(Async::Ready(()));
                 }
-                Async::Ready(Phase::B(Some(request))) => {
+                Async::Ready(Phase::B(IncomingRequest::HeaderTimeout)) => {
+                    let stream = self.stream.take().expect("Never fails");
+                    let bytes = raw_problem_response(408, "Request Timeout");
+                    let future: BoxFuture<_, _> = Box::new(stream.write_all_bytes(bytes));
+                    Phase::E(future)
+                }
+                Async::Ready(Phase::B(IncomingRequest::HeaderTooLarge)) => {
+                    // NOTE: The connection can not be reused because the request is partially read.
+                    let stream = self.stream.take().expect("Never fails");
+                    let bytes = raw_problem_response(431, "Request Header Fields Too Large");
+                    let future: BoxFuture<_, _> = Box::new(stream.write_all_bytes(bytes));
+                    Phase::E(future)
+                }
+                Async::Ready(Phase::B(IncomingRequest::Request(request)))
+                    if request.path().len() > self.options.max_uri_length =>
+                {
+                    let problem = Problem::about_blank(HttpStatus::UriTooLong);
+                    Phase::C(discard_body_and_respond(
+                        request,
+                        self.options.max_body_size,
+                        self.options.read_body_timeout,
+                        problem.into_response(),
+                        MiddlewareChain::default(),
+                    ))
+                }
+                Async::Ready(Phase::B(IncomingRequest::Request(request))) => {
                     debug!(
-                        self.logger,
+                        self.options.logger,
                         "RPC request: method={}, path={:?}",
                         request.method(),
                         request.path(),
@@ -210,47 +884,74 @@ impl HandleHttpRequest {
                     let future: BoxFuture<_, _> =
                         match track!(misc::parse_relative_url(request.path())) {
                             Err(e) => {
-                                let future = futures::done(request.into_body_reader())
-                                    .map_err(Error::from)
-                                    .and_then(|request| {
-                                        request.read_all_bytes().map_err(Error::from)
-                                    })
-                                    .and_then(|(request, _)| {
-                                        let request = request.into_inner();
-                                        let rpc_response =
-                                            Problem::trackable(HttpStatus::BadRequest, e)
-                                                .into_response();
-                                        track!(RpcResponseSerializer::serialize(
-                                            rpc_response,
-                                            request.finish(),
-                                        ))
-                                    });
-                                Box::new(future)
+                                let problem = Problem::trackable(HttpStatus::BadRequest, e);
+                                discard_body_and_respond(
+                                    request,
+                                    self.options.max_body_size,
+                                    self.options.read_body_timeout,
+                                    problem.into_response(),
+                                    MiddlewareChain::default(),
+                                )
                             }
                             Ok(url) => match self.router.route(&url, &request) {
-                                Err(status) => {
-                                    let future = futures::done(request.into_body_reader())
-                                        .map_err(Error::from)
-                                        .and_then(|request| {
-                                            request.read_all_bytes().map_err(Error::from)
-                                        })
-                                        .and_then(move |(request, _)| {
-                                            let request = request.into_inner();
-                                            let rpc_response =
-                                                Problem::about_blank(status).into_response();
-                                            track!(RpcResponseSerializer::serialize(
-                                                rpc_response,
-                                                request.finish(),
-                                            ))
-                                        });
-                                    Box::new(future)
+                                Err(RouteError::NotFound) => discard_body_and_respond(
+                                    request,
+                                    self.options.max_body_size,
+                                    self.options.read_body_timeout,
+                                    Problem::about_blank(HttpStatus::NotFound).into_response(),
+                                    MiddlewareChain::default(),
+                                ),
+                                Err(RouteError::MethodNotAllowed { allow }) => {
+                                    let mut response = if request.method() == HttpMethod::Options {
+                                        RawResponse::new(HttpStatus::Ok)
+                                    } else {
+                                        let problem =
+                                            Problem::about_blank(HttpStatus::MethodNotAllowed);
+                                        RawResponse::from_problem(&problem)
+                                    };
+                                    let allow = allow
+                                        .iter()
+                                        .map(|m| m.as_str())
+                                        .collect::<Vec<_>>()
+                                        .join(", ");
+                                    response.add_header("allow", &allow);
+                                    discard_body_and_respond(
+                                        request,
+                                        self.options.max_body_size,
+                                        self.options.read_body_timeout,
+                                        response,
+                                        MiddlewareChain::default(),
+                                    )
+                                }
+                                Ok(route) => {
+                                    if let Some(in_flight) = InFlightRequest::new(&self.options) {
+                                        self.in_flight = Some(in_flight);
+                                        route.handle(url, request, &self.options, self.peer_addr)
+                                    } else {
+                                        warn!(
+                                            self.options.logger,
+                                            "Too many in-flight requests: limit={:?}",
+                                            self.options.max_in_flight_requests
+                                        );
+                                        let mut response =
+                                            Problem::about_blank(HttpStatus::ServiceUnavailable)
+                                                .into_response();
+                                        response.set_retry_after(self.options.retry_after);
+                                        discard_body_and_respond(
+                                            request,
+                                            self.options.max_body_size,
+                                            self.options.read_body_timeout,
+                                            response,
+                                            MiddlewareChain::default(),
+                                        )
+                                    }
                                 }
-                                Ok(handler) => handler(url, request),
                             },
                         };
                     Phase::C(future)
                 }
                 Async::Ready(Phase::C((response, body))) => {
+                    self.in_flight = None;
                     let future: BoxFuture<_, _> = if self.method == HttpMethod::Head {
                         Box::new(response)
                     } else {
@@ -264,18 +965,1109 @@ impl HandleHttpRequest {
                     };
                     Phase::A(future)
                 }
-                _ => unreachable!(),
+                Async::Ready(Phase::D(stream)) => {
+                    let _ = stream.with_inner(|inner| inner.set_nodelay(true));
+                    self.stream = Some(stream.clone());
+                    let connection = Connection::new(
+                        stream,
+                        self.options.initial_buffer_size,
+                        self.options.max_header_bytes,
+                        self.options.max_header_count,
+                    );
+                    Phase::B(self.read_request(connection, false))
+                }
+                Async::Ready(Phase::E(_)) => {
+                    return Ok(Async::Ready(()));
+                }
             };
             self.phase = next;
         }
     }
+    fn read_request(
+        &self,
+        connection: Connection<TcpStream>,
+        is_idle: bool,
+    ) -> BoxFuture<IncomingRequest, miasht::Error> {
+        let header_timeout = self.options.read_header_timeout;
+        if !is_idle {
+            return read_request_head(connection, header_timeout);
+        }
+
+        // NOTE: The keep-alive timeout is applied only until the next request arrives,
+        // and the rest of the head is read within the header timeout.
+        let future = with_timeout(
+            WaitNextRequest(Some(connection)),
+            self.options.keep_alive_timeout,
+        )
+        .then(move |result| match result {
+            Ok(connection) => read_request_head(connection, header_timeout),
+            Err(None) => Box::new(futures::finished(IncomingRequest::IdleTimeout)),
+            Err(Some(e)) => Box::new(futures::done(handle_read_request_error(e))),
+        });
+        Box::new(future)
+    }
 }
 impl Future for HandleHttpRequest {
     type Item = ();
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.poll_impl().map_err(|e| {
-            warn!(self.logger, "Failed to handle RPC request: {}", e);
+            warn!(self.options.logger, "Failed to handle RPC request: {}", e);
         })
     }
 }
+impl Drop for HandleHttpRequest {
+    fn drop(&mut self) {
+        let _ = self
+            .command_tx
+            .send(Command::ConnectionClosed { id: self.id });
+    }
+}
+
+/// A guard which represents a request being handled by an RPC handler.
+struct InFlightRequest(Arc<AtomicUsize>);
+impl InFlightRequest {
+    fn new(options: &ServerOptions) -> Option<Self> {
+        let counter = &options.in_flight_requests;
+        if let Some(max) = options.max_in_flight_requests {
+            let mut current = counter.load(Ordering::SeqCst);
+            loop {
+                if current >= max {
+                    return None;
+                }
+                match counter.compare_exchange(
+                    current,
+                    current + 1,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        } else {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        Some(InFlightRequest(Arc::clone(counter)))
+    }
+}
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+type HttpResponse = (Response<TcpStream>, Box<dyn AsRef<[u8]> + Send + 'static>);
+
+type WithTimeout<F> =
+    Either<TimeoutAfter<F>, MapErr<F, fn(<F as Future>::Error) -> Option<<F as Future>::Error>>>;
+
+type ConnectionPhase = Phase<
+    BoxFuture<Connection<TcpStream>, miasht::Error>,
+    BoxFuture<IncomingRequest, miasht::Error>,
+    BoxFuture<HttpResponse, Error>,
+    Connected,
+    BoxFuture<TcpStream, miasht::Error>,
+>;
+
+enum IncomingRequest {
+    Request(Request<TcpStream>),
+    Eof,
+    HeaderTooLarge,
+    HeaderTimeout,
+    IdleTimeout,
+}
+
+/// Reads the head of the next request within `timeout`.
+fn read_request_head(
+    connection: Connection<TcpStream>,
+    timeout: Option<Duration>,
+) -> BoxFuture<IncomingRequest, miasht::Error> {
+    let future = with_timeout(connection.read_request(), timeout)
+        .map(IncomingRequest::Request)
+        .or_else(|e| match e {
+            None => Ok(IncomingRequest::HeaderTimeout),
+            Some(e) => handle_read_request_error(e),
+        });
+    Box::new(future)
+}
+
+/// Converts the errors which occurred while reading a request head into `IncomingRequest`s.
+fn handle_read_request_error(
+    e: miasht::Error,
+) -> ::std::result::Result<IncomingRequest, miasht::Error> {
+    if let Some(e) = e.concrete_cause::<io::Error>() {
+        if e.kind() == io::ErrorKind::UnexpectedEof || e.kind() == io::ErrorKind::ConnectionReset {
+            // The connection is reset by the peer.
+            return Ok(IncomingRequest::Eof);
+        }
+        if e.kind() == io::ErrorKind::WriteZero {
+            // The buffer for the request head is overflowed
+            // (miasht reports it as `WriteZero`; see `default_header_limits_work`).
+            return Ok(IncomingRequest::HeaderTooLarge);
+        }
+    }
+    if let Some(&httparse::Error::TooManyHeaders) = e.concrete_cause::<httparse::Error>() {
+        return Ok(IncomingRequest::HeaderTooLarge);
+    }
+    Err(e)
+}
+
+/// A future which waits until the next request arrives on a keep-alive connection.
+struct WaitNextRequest(Option<Connection<TcpStream>>);
+impl Future for WaitNextRequest {
+    type Item = Connection<TcpStream>;
+    type Error = miasht::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut connection = self.0.take().expect("Cannot poll WaitNextRequest twice");
+        let arrived = {
+            let inner = connection.as_mut();
+            // NOTE: The bytes of pipelined requests may have already been buffered.
+            inner.buffer.enter_read_phase();
+            !inner.buffer.is_empty() || track!(inner.fill_buffer().map_err(miasht::Error::from))?
+        };
+        if arrived {
+            Ok(Async::Ready(connection))
+        } else {
+            self.0 = Some(connection);
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Reads and discards the body of `request`, then responds with `response`.
+fn discard_body_and_respond<R>(
+    request: Request<TcpStream>,
+    max_body_size: Option<u64>,
+    read_body_timeout: Option<Duration>,
+    response: R,
+    middlewares: MiddlewareChain,
+) -> BoxFuture<HttpResponse, Error>
+where
+    R: RpcResponse + Send + 'static,
+{
+    let read_body = futures::done(BodyReader::new(request, max_body_size))
+        .and_then(|request| request.read_all_bytes().map_err(Error::from));
+    let future =
+        with_read_body_timeout(read_body, read_body_timeout).and_then(move |(request, _)| {
+            let request = request.into_inner();
+            track!(serialize_response(response, request.finish(), &middlewares))
+        });
+    Box::new(future)
+}
+
+/// Serializes `rpc_response` after applying the middlewares to it.
+fn serialize_response<T: RpcResponse>(
+    mut rpc_response: T,
+    connection: Connection<TcpStream>,
+    middlewares: &MiddlewareChain,
+) -> Result<HttpResponse> {
+    let mut serializer = RpcResponseSerializer::new(connection);
+    track!(rpc_response.serialize(&mut serializer))?;
+    let body = rpc_response.body();
+    track!(serializer.finish_with(body, |status, body, headers| {
+        middlewares.after_handle(status, body, headers)
+    }))
+}
+
+/// Adds `timeout` to `future` if it is specified.
+///
+/// If the timeout expires, the resulting future will fail with `None`.
+fn with_timeout<F: Future>(future: F, timeout: Option<Duration>) -> WithTimeout<F> {
+    if let Some(timeout) = timeout {
+        Either::A(future.timeout_after(timeout))
+    } else {
+        Either::B(future.map_err(Some))
+    }
+}
+
+/// Adds the timeout for reading a request body to `future`.
+fn with_read_body_timeout<F>(future: F, timeout: Option<Duration>) -> BoxFuture<F::Item, Error>
+where
+    F: Future<Error = Error> + Send + 'static,
+{
+    let future = with_timeout(future, timeout)
+        .map_err(|e| e.unwrap_or_else(|| track!(Error::from(ReadBodyTimeout))));
+    Box::new(future)
+}
+
+/// Makes the raw bytes of a problem response which closes the connection.
+///
+/// This is used when the connection is in a state that a response can not be built normally
+/// (e.g., the request is partially read), or `HttpStatus` does not define the status.
+fn raw_problem_response(status: u16, reason: &str) -> Vec<u8> {
+    let problem = Problem::AboutBlank(AboutBlankProblem {
+        title: reason.to_owned(),
+        status,
+    });
+    let body = serdeconv::to_json_string_pretty(&problem).expect("Never fails");
+    let mut bytes = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: application/problem+json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    )
+    .into_bytes();
+    bytes.extend_from_slice(body.as_bytes());
+    bytes
+}
+
+fn panic_response<P: Procedure>(
+    panic: &(dyn Any + Send),
+    entry_point: &EntryPoint,
+    options: &ServerOptions,
+    connection: Connection<TcpStream>,
+    middlewares: &MiddlewareChain,
+) -> Result<HttpResponse> {
+    let message = if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown".to_owned()
+    };
+    error!(
+        options.logger,
+        "RPC handler panicked: method={}, entry_point={:?}, message={:?}",
+        P::method(),
+        entry_point,
+        message
+    );
+    let problem = if options.debug_mode {
+        let e = ErrorKind::Other.cause(format!("Handler panicked: {}", message));
+        Problem::trackable(HttpStatus::InternalServerError, Error::from(e))
+    } else {
+        Problem::about_blank(HttpStatus::InternalServerError)
+    };
+    track!(serialize_response(
+        problem.into_response(),
+        connection,
+        middlewares
+    ))
+}
+
+/// A handle for `RpcServer`.
+#[derive(Debug, Clone)]
+pub struct RpcServerHandle {
+    command_tx: mpsc::Sender<Command>,
+    local_addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+impl RpcServerHandle {
+    /// Returns the address to which the server is bound.
+    ///
+    /// This is useful if the server is bound to the port `0`.
+    /// `None` is returned until the server starts listening.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr.lock().ok().and_then(|addr| *addr)
+    }
+
+    /// Starts the graceful shutdown of the server.
+    ///
+    /// The server stops accepting new connections and closes idle keep-alive connections.
+    /// In-flight requests are allowed to finish within the grace period
+    /// (see `RpcServerBuilder::set_shutdown_grace_period`),
+    /// after that the `RpcServer` future will be completed.
+    pub fn shutdown(&self) {
+        let _ = self.command_tx.send(Command::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fibers::{Executor, InPlaceExecutor};
+    use futures::future::FutureResult;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::sync::mpsc as std_mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use middleware::{RawResponse, ResponseHead};
+    use rfc7807::Problem;
+    use slog::{Drain, Never, OwnedKVList, Record};
+    use test_util::*;
+    use RpcClient;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct FallibleHelloHandler;
+    impl HandleFallibleRpc<Hello> for FallibleHelloHandler {
+        type Error = Problem;
+        type Future = BoxFuture<HelloResponse, Problem>;
+        fn handle_rpc(self, request: HelloRequest) -> Self::Future {
+            let (name,) = request.path;
+            if name == "panic" {
+                panic!("Hello panic");
+            } else if name == "panic-in-future" {
+                let future = futures::finished::<(), Problem>(());
+                Box::new(future.map(|()| panic!("Hello future panic")))
+            } else if name == "slow" {
+                Box::new(futures::empty())
+            } else if name == "nobody" {
+                Box::new(futures::failed(Problem::about_blank(
+                    HttpStatus::BadRequest,
+                )))
+            } else {
+                let body = format!("Hello {}", name).into_bytes();
+                Box::new(futures::finished(HelloResponse::Ok { body }))
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_works() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut builder = RpcServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.set_shutdown_grace_period(Duration::from_secs(10));
+        let server = builder.start(executor.handle());
+        let handle = server.handle();
+        handle.shutdown();
+
+        let result = executor.run_future(server.timeout_after(Duration::from_secs(5)));
+        assert!(result.unwrap().is_ok());
+    }
+
+    /// Notifies `started` when called, then responds after `delay` (or never if it is `None`).
+    #[derive(Clone)]
+    struct NotifyingHelloHandler {
+        started: Arc<Mutex<std_mpsc::Sender<()>>>,
+        delay: Option<Duration>,
+    }
+    impl NotifyingHelloHandler {
+        fn new(delay: Option<Duration>) -> (Self, std_mpsc::Receiver<()>) {
+            let (tx, rx) = std_mpsc::channel();
+            let handler = NotifyingHelloHandler {
+                started: Arc::new(Mutex::new(tx)),
+                delay,
+            };
+            (handler, rx)
+        }
+    }
+    impl HandleRpc<Hello> for NotifyingHelloHandler {
+        type Future = BoxFuture<HelloResponse, NeverFail>;
+        fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+            let _ = self.started.lock().unwrap().send(());
+            if let Some(delay) = self.delay {
+                let body = b"done".to_vec();
+                Box::new(timer::timeout(delay).then(|_| Ok(HelloResponse::Ok { body })))
+            } else {
+                Box::new(futures::empty())
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_waits_for_in_flight_requests() {
+        let (handler, started) = NotifyingHelloHandler::new(Some(Duration::from_millis(200)));
+        let mut builder = server_builder();
+        builder.set_shutdown_grace_period(Duration::from_secs(10));
+        builder.register(handler, Hello).unwrap();
+        let server = spawn_server(builder);
+
+        let mut stream = net::TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        server.handle.shutdown();
+
+        // The in-flight request completes, then the connection is closed.
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf).into_owned();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\ndone"), "{}", response);
+        assert!(server.wait_stopped(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shutdown_grace_period_works() {
+        let (handler, started) = NotifyingHelloHandler::new(None);
+        let mut builder = server_builder();
+        builder.set_shutdown_grace_period(Duration::from_millis(100));
+        builder.register(handler, Hello).unwrap();
+        let server = spawn_server(builder);
+
+        let mut stream = net::TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        server.handle.shutdown();
+
+        // The connection is forcibly closed without a response.
+        assert!(server.wait_stopped(Duration::from_secs(5)));
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty(), "{}", String::from_utf8_lossy(&buf));
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let mut builder = server_builder();
+        builder.set_shutdown_grace_period(Duration::from_secs(10));
+        builder.register(NamedHelloHandler("foo"), Hello).unwrap();
+        let server = spawn_server(builder);
+
+        let mut stream = net::TcpStream::connect(server.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // The idle keep-alive connection is closed without waiting for the grace period.
+        server.handle.shutdown();
+        assert!(server.wait_stopped(Duration::from_secs(5)));
+        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 0);
+    }
+
+    #[test]
+    fn fallible_handler_works() {
+        let mut builder = server_builder();
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut client = RpcClient::new(addr);
+        let request = hello("world");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"Hello world"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let request = hello("nobody");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::BadRequest { body } => {
+                assert!(String::from_utf8(body).unwrap().contains("about:blank"));
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[derive(Clone)]
+    struct ErrorHelloHandler;
+    impl HandleFallibleRpc<Hello> for ErrorHelloHandler {
+        type Error = Error;
+        type Future = FutureResult<HelloResponse, Error>;
+        fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+            let e = ErrorKind::Other.cause("Hello error");
+            futures::failed(track!(Error::from(e)))
+        }
+    }
+
+    #[test]
+    fn error_response_works() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        for &debug_mode in &[false, true] {
+            let mut builder = server_builder();
+            builder.set_debug_mode(debug_mode);
+            builder.register_fallible(ErrorHelloHandler, Hello).unwrap();
+            let addr = spawn_server(builder).addr;
+
+            let mut client = RpcClient::new(addr);
+            let monitor = executor.spawn_monitor(client.call::<Hello>(hello("world")));
+            match executor.run_future(monitor).unwrap().unwrap() {
+                HelloResponse::InternalServerError { body } => {
+                    let body = String::from_utf8(body).unwrap();
+                    assert_eq!(body.contains("Hello error"), debug_mode, "{}", body);
+                    assert_eq!(body.contains("src/server.rs"), debug_mode, "{}", body);
+                }
+                r => panic!("Unexpected response: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn handler_panic_works() {
+        let mut builder = server_builder();
+        builder.set_debug_mode(true);
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut client = RpcClient::new(addr);
+        let request = hello("panic");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::InternalServerError { body } => {
+                assert!(String::from_utf8(body).unwrap().contains("Hello panic"));
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let request = hello("panic-in-future");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::InternalServerError { body } => {
+                assert!(String::from_utf8(body)
+                    .unwrap()
+                    .contains("Hello future panic"));
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn handler_panic_keeps_connection_alive() {
+        let mut builder = server_builder();
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for name in &["panic", "panic-in-future"] {
+            let request = format!("GET /hello/{} HTTP/1.1\r\nContent-Length: 0\r\n\r\n", name);
+            stream.write_all(request.as_bytes()).unwrap();
+            let response = read_response(&mut stream);
+            assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+
+            // The connection still serves the next request.
+            stream
+                .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let response = read_response(&mut stream);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\nHello foo"), "{}", response);
+        }
+    }
+
+    /// A drain which collects the messages of log records.
+    #[derive(Clone, Default)]
+    struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+    impl Drain for CapturingDrain {
+        type Ok = ();
+        type Err = Never;
+        fn log(&self, record: &Record, _: &OwnedKVList) -> ::std::result::Result<(), Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handler_logs_matched_entry_point() {
+        let mut options = ProcedureOptions::new();
+        options.set_handler_timeout(Duration::from_millis(50));
+        let mut procedures = server_builder();
+        procedures
+            .register_fallible_with_options(FallibleHelloHandler, Hello, options)
+            .unwrap();
+        let drain = CapturingDrain::default();
+        let mut builder = server_builder();
+        builder.set_logger(Logger::root(drain.clone(), o!()));
+        builder
+            .mount(&htrpc_entry_point!["v1", "greeting"], procedures)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let response = send_raw_request(
+            addr,
+            b"GET /v1/greeting/hello/panic HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+        let response = send_raw_request(
+            addr,
+            b"GET /v1/greeting/hello/slow HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+
+        let messages = drain.0.lock().unwrap().clone();
+        let expected = format!("{:?}", htrpc_entry_point!["v1", "greeting", "hello", _]);
+        for prefix in &["RPC handler panicked", "RPC handler timed out"] {
+            let message = messages.iter().find(|m| m.starts_with(prefix));
+            assert!(
+                message.is_some_and(|m| m.contains(&expected)),
+                "{:?}",
+                messages
+            );
+        }
+    }
+
+    fn send_raw_request(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        read_response(&mut stream)
+    }
+
+    /// Reads until the whole response (the head and the body) is received.
+    fn read_response(stream: &mut net::TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let size = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => size,
+            };
+            response.extend_from_slice(&buf[..size]);
+
+            let text = String::from_utf8_lossy(&response).into_owned();
+            if let Some(head_end) = text.find("\r\n\r\n") {
+                let content_length = text[..head_end]
+                    .lines()
+                    .filter_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        if name.eq_ignore_ascii_case("content-length") {
+                            value.trim().parse::<usize>().ok()
+                        } else {
+                            None
+                        }
+                    })
+                    .next()
+                    .unwrap_or(0);
+                if response.len() >= head_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn header_limits_work() {
+        let mut builder = server_builder();
+        builder.set_max_header_count(4);
+        builder.set_max_uri_length(16);
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let mut request = b"GET /hello/foo HTTP/1.1\r\n".to_vec();
+        for i in 0..5 {
+            request.extend_from_slice(format!("X-Foo-{}: bar\r\n", i).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        let response = send_raw_request(addr, &request);
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+
+        let response = send_raw_request(addr, b"GET /hello/too-long-name HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 414 "), "{}", response);
+    }
+
+    #[test]
+    fn default_header_limits_work() {
+        let mut builder = server_builder();
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        // Too long URI
+        let request = format!("GET /hello/{} HTTP/1.1\r\n\r\n", "a".repeat(5000));
+        let response = send_raw_request(addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 414 "), "{}", response);
+
+        // Too large headers (in bytes)
+        let request = format!(
+            "GET /hello/foo HTTP/1.1\r\nX-Foo: {}\r\n\r\n",
+            "a".repeat(9000)
+        );
+        let response = send_raw_request(addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+    }
+
+    #[test]
+    fn body_size_limits_work() {
+        let mut builder = server_builder();
+        builder.set_max_body_size(16);
+        let mut options = ProcedureOptions::new();
+        options.set_max_body_size(8);
+        builder
+            .register_fallible_with_options(FallibleHelloHandler, Hello, options)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 8\r\n\r\n01234567",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 9\r\n\r\n012345678",
+        );
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              9\r\n012345678\r\n0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        let response = send_raw_request(
+            addr,
+            b"GET /foo HTTP/1.1\r\nContent-Length: 17\r\n\r\n0123456789abcdefg",
+        );
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        // Effectively unlimited
+        let mut builder = server_builder();
+        builder.set_max_body_size(u64::MAX);
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 17\r\n\r\n0123456789abcdefg",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn timeouts_work() {
+        let mut builder = server_builder();
+        builder.set_read_header_timeout(Duration::from_millis(100));
+        builder.set_read_body_timeout(Duration::from_millis(100));
+        builder.set_keep_alive_timeout(Duration::from_millis(100));
+        builder.set_handler_timeout(Duration::from_secs(10));
+        let mut options = ProcedureOptions::new();
+        options.set_handler_timeout(Duration::from_millis(100));
+        builder
+            .register_with_options(SlowHelloHandler, Hello, options)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        // Handler timeout
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/slow HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+
+        // Header read timeout
+        let response = send_raw_request(addr, b"GET /hello/foo HTTP/1.1\r\n");
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+
+        // Body read timeout
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 10\r\n\r\n01234",
+        );
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+
+        // Keep-alive timeout
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap(); // The connection is closed by the server
+        assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn keep_alive_header_timeout_works() {
+        let mut builder = server_builder();
+        builder.set_read_header_timeout(Duration::from_millis(200));
+        builder.set_keep_alive_timeout(Duration::from_secs(10));
+        builder.register(SlowHelloHandler, Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n";
+        stream.write_all(request).unwrap();
+        let mut buf = Vec::new();
+        while !buf.ends_with(b"Hello foo") {
+            let mut bytes = [0; 1024];
+            let size = stream.read(&mut bytes).unwrap();
+            assert_ne!(size, 0);
+            buf.extend_from_slice(&bytes[..size]);
+        }
+        assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // The second request head trickles in on the same connection
+        for &b in &request[..request.len() - 4] {
+            if stream.write_all(&[b]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        let response = String::from_utf8(buf).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    }
+
+    #[test]
+    fn admission_control_works() {
+        let mut builder = server_builder();
+        builder.set_max_connections(2);
+        builder.set_max_in_flight_requests(1);
+        builder.set_retry_after(Duration::from_secs(3));
+        builder.set_handler_timeout(Duration::from_millis(500));
+        builder.register(SlowHelloHandler, Hello).unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let mut slow = net::TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET /hello/slow HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // Too many in-flight requests
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        stream
+            .write_all(b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let mut buf = [0; 1024];
+        let size = stream.read(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf[..size]).into_owned();
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(response.contains("\r\nretry-after: 3\r\n"), "{}", response);
+
+        // Too many connections
+        let mut pending = net::TcpStream::connect(addr).unwrap();
+        pending
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        pending
+            .write_all(b"GET /hello/bar HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        assert!(pending.read(&mut buf).is_err());
+
+        // Closing a connection resumes accepting
+        drop(stream);
+        pending
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let size = pending.read(&mut buf).unwrap();
+        assert!(buf[..size].starts_with(b"HTTP/1.1 "));
+
+        let size = slow.read(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf[..size]).into_owned();
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    }
+
+    struct ServedBy;
+    impl Middleware for ServedBy {
+        fn after_handle(&self, context: &RequestContext, response: &mut ResponseHead) {
+            assert_eq!(*context.entry_point(), Hello::entry_point());
+            let value = format!("htrpc ({})", response.status());
+            response.add_header("X-Served-By", value.as_bytes());
+        }
+    }
+
+    struct User(&'static str);
+
+    struct Auth;
+    impl Middleware for Auth {
+        fn before_handle(&self, context: &mut RequestContext) -> Option<RawResponse> {
+            if context.header("x-token") == Some(b"secret") {
+                context.extensions_mut().insert(User("alice"));
+                None
+            } else {
+                let problem = Problem::about_blank(HttpStatus::Unauthorized);
+                Some(RawResponse::from_problem(&problem))
+            }
+        }
+    }
+
+    #[test]
+    fn middlewares_work() {
+        let mut builder = server_builder();
+        builder.add_middleware(ServedBy);
+        let mut options = ProcedureOptions::new();
+        options.add_middleware(Auth);
+        builder
+            .register_fallible_with_options(FallibleHelloHandler, Hello, options)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        // Short-circuited by `Auth`
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 401 "), "{}", response);
+        assert!(
+            response.contains("\r\nX-Served-By: htrpc (401)\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nX-Token: secret\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("\r\nX-Served-By: htrpc (200)\r\n"),
+            "{}",
+            response
+        );
+
+        // Responses generated by the server itself bypass middlewares
+        let response = send_raw_request(addr, b"GET /foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+        assert!(!response.contains("X-Served-By"), "{}", response);
+    }
+
+    #[derive(Clone)]
+    struct ContextHelloHandler;
+    impl HandleRpcWithContext<Hello> for ContextHelloHandler {
+        type Error = NeverFail;
+        type Future = FutureResult<HelloResponse, NeverFail>;
+        fn handle_rpc(self, context: &RequestContext, request: HelloRequest) -> Self::Future {
+            let (name,) = request.path;
+            let user = context
+                .extensions()
+                .get::<User>()
+                .map_or("anonymous", |u| u.0);
+            let body = format!(
+                "Hello {} ({} {} {}) from {} at {}",
+                name,
+                context.method(),
+                context.url().path(),
+                String::from_utf8_lossy(context.header("x-token").unwrap_or(b"")),
+                user,
+                context.peer_addr().ip(),
+            );
+            futures::finished(HelloResponse::Ok {
+                body: body.into_bytes(),
+            })
+        }
+    }
+
+    #[test]
+    fn request_context_works() {
+        let mut builder = server_builder();
+        builder.add_middleware(Auth);
+        builder
+            .register_with_context(ContextHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let response = send_raw_request(
+            addr,
+            b"GET /hello/foo HTTP/1.1\r\nX-Token: secret\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nHello foo (GET /hello/foo secret) from alice at 127.0.0.1"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn method_not_allowed_works() {
+        let mut builder = server_builder();
+        builder
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+
+        let response = send_raw_request(
+            addr,
+            b"POST /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+        assert!(
+            response.contains("\r\nallow: GET, OPTIONS\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw_request(
+            addr,
+            b"OPTIONS /hello/foo HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("\r\nallow: GET, OPTIONS\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send_raw_request(
+            addr,
+            b"OPTIONS /hello HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    }
+
+    #[test]
+    fn mount_works() {
+        let mut procedures = server_builder();
+        procedures
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        let mut builder = server_builder();
+        builder
+            .mount(&htrpc_entry_point!["v1", "greeting"], procedures)
+            .unwrap();
+        let addr = spawn_server(builder).addr;
+        let mut executor = InPlaceExecutor::new().unwrap();
+
+        let mut client = RpcClient::new(addr);
+        client
+            .set_base_path(htrpc_entry_point!["v1", "greeting"])
+            .unwrap();
+        let request = hello("world");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        match executor.run_future(monitor).unwrap().unwrap() {
+            HelloResponse::Ok { body } => assert_eq!(body, b"Hello world"),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let mut client = RpcClient::new(addr);
+        let request = hello("world");
+        let monitor = executor.spawn_monitor(client.call::<Hello>(request));
+        let result = executor.run_future(monitor).unwrap();
+        assert!(result.is_err(), "{:?}", result.map(|_| ()));
+        assert!(client.set_base_path(htrpc_entry_point!["v1", _]).is_err());
+
+        let mut builder = server_builder();
+        let mut procedures = server_builder();
+        procedures
+            .register_fallible(FallibleHelloHandler, Hello)
+            .unwrap();
+        assert!(builder
+            .mount(&htrpc_entry_point!["v1", _], procedures)
+            .is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Fixtures shared by the tests of the server and the clients.
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use fibers::time::timer;
+use fibers::{Executor, Spawn, ThreadPoolExecutor};
+use futures::future::FutureResult;
+use futures::{self, Future};
+use miasht::builtin::futures::FutureExt;
+
+use procedure::{
+    HandleFallibleRpc, HandleRpc, HandleRpcWithContext, IntoErrorResponse, NeverFail, Procedure,
+};
+use rfc7807::{Problem, ProblemResponse};
+use types::{EntryPoint, HttpMethod, HttpStatus};
+use {
+    BodyReader, Error, ReadBody, RequestContext, RpcRequest, RpcResponse, RpcServerBuilder,
+    RpcServerHandle,
+};
+
+type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send + 'static>;
+
+pub struct Hello;
+impl Procedure for Hello {
+    type Request = HelloRequest;
+    type Response = HelloResponse;
+    fn method() -> HttpMethod {
+        HttpMethod::Get
+    }
+    fn entry_point() -> EntryPoint {
+        htrpc_entry_point!["hello", _]
+    }
+}
+
+pub struct PostHello;
+impl Procedure for PostHello {
+    type Request = HelloRequest;
+    type Response = HelloResponse;
+    fn method() -> HttpMethod {
+        HttpMethod::Post
+    }
+    fn entry_point() -> EntryPoint {
+        htrpc_entry_point!["hello", _]
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HelloRequest {
+    pub path: (String,),
+}
+impl RpcRequest for HelloRequest {
+    fn body(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn read_body(self, body: BodyReader) -> ReadBody<Self> {
+        Box::new(
+            body.read_all_bytes()
+                .map_err(Error::from)
+                .map(move |(body, _)| (body, self)),
+        )
+    }
+}
+
+/// Makes a request of which path is `/hello/{name}`.
+pub fn hello(name: &str) -> HelloRequest {
+    HelloRequest {
+        path: (name.to_owned(),),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok {
+        #[serde(skip)]
+        body: Vec<u8>,
+    },
+    BadRequest {
+        #[serde(skip)]
+        body: Vec<u8>,
+    },
+    InternalServerError {
+        #[serde(skip)]
+        body: Vec<u8>,
+    },
+    ServiceUnavailable {
+        #[serde(skip)]
+        body: Vec<u8>,
+    },
+}
+impl RpcResponse for HelloResponse {
+    fn body(&mut self) -> Box<dyn AsRef<[u8]> + Send + 'static> {
+        match *self {
+            HelloResponse::Ok { ref mut body }
+            | HelloResponse::BadRequest { ref mut body }
+            | HelloResponse::InternalServerError { ref mut body }
+            | HelloResponse::ServiceUnavailable { ref mut body } => {
+                Box::new(::std::mem::take(body))
+            }
+        }
+    }
+    fn set_body(&mut self, bytes: Vec<u8>) {
+        match *self {
+            HelloResponse::Ok { ref mut body }
+            | HelloResponse::BadRequest { ref mut body }
+            | HelloResponse::InternalServerError { ref mut body }
+            | HelloResponse::ServiceUnavailable { ref mut body } => {
+                *body = bytes;
+            }
+        }
+    }
+}
+
+/// Never responds if the name is `slow`.
+#[derive(Clone)]
+pub struct SlowHelloHandler;
+impl HandleRpc<Hello> for SlowHelloHandler {
+    type Future = BoxFuture<HelloResponse, NeverFail>;
+    fn handle_rpc(self, request: HelloRequest) -> Self::Future {
+        let (name,) = request.path;
+        if name == "slow" {
+            Box::new(futures::empty())
+        } else {
+            let body = format!("Hello {}", name).into_bytes();
+            Box::new(futures::finished(HelloResponse::Ok { body }))
+        }
+    }
+}
+
+/// Responds after the delay.
+#[derive(Clone)]
+pub struct DelayedHelloHandler(pub Duration);
+impl HandleRpc<Hello> for DelayedHelloHandler {
+    type Future = BoxFuture<HelloResponse, NeverFail>;
+    fn handle_rpc(self, request: HelloRequest) -> Self::Future {
+        let (name,) = request.path;
+        let body = format!("Hello {}", name).into_bytes();
+        let future = timer::timeout(self.0).then(move |_| Ok(HelloResponse::Ok { body }));
+        Box::new(future)
+    }
+}
+
+/// Responds with the port number of the client.
+#[derive(Clone)]
+pub struct PeerPortHandler;
+impl HandleRpcWithContext<Hello> for PeerPortHandler {
+    type Error = NeverFail;
+    type Future = FutureResult<HelloResponse, NeverFail>;
+    fn handle_rpc(self, context: &RequestContext, _request: HelloRequest) -> Self::Future {
+        let body = context.peer_addr().port().to_string().into_bytes();
+        futures::finished(HelloResponse::Ok { body })
+    }
+}
+
+/// Returns `503 Service Unavailable` until it is called `fail_count` times.
+#[derive(Clone)]
+pub struct FlakyHelloHandler {
+    pub calls: Arc<AtomicUsize>,
+    pub fail_count: usize,
+}
+impl FlakyHelloHandler {
+    pub fn new(fail_count: usize) -> Self {
+        FlakyHelloHandler {
+            calls: Arc::new(AtomicUsize::new(0)),
+            fail_count,
+        }
+    }
+    fn handle(&self) -> FutureResult<HelloResponse, NeverFail> {
+        let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let body = Vec::new();
+        if calls <= self.fail_count {
+            futures::finished(HelloResponse::ServiceUnavailable { body })
+        } else {
+            futures::finished(HelloResponse::Ok { body })
+        }
+    }
+}
+impl HandleRpc<Hello> for FlakyHelloHandler {
+    type Future = FutureResult<HelloResponse, NeverFail>;
+    fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+        self.handle()
+    }
+}
+impl HandleRpc<PostHello> for FlakyHelloHandler {
+    type Future = FutureResult<HelloResponse, NeverFail>;
+    fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+        self.handle()
+    }
+}
+
+/// Returns `503 Service Unavailable` with the `Retry-After` header for the first call.
+#[derive(Clone)]
+pub struct BusyHelloHandler {
+    pub calls: Arc<AtomicUsize>,
+    pub retry_after: Duration,
+}
+impl BusyHelloHandler {
+    pub fn new(retry_after: Duration) -> Self {
+        BusyHelloHandler {
+            calls: Arc::new(AtomicUsize::new(0)),
+            retry_after,
+        }
+    }
+}
+impl HandleFallibleRpc<Hello> for BusyHelloHandler {
+    type Error = Busy;
+    type Future = FutureResult<HelloResponse, Busy>;
+    fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+        if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            futures::failed(Busy(self.retry_after))
+        } else {
+            futures::finished(HelloResponse::Ok { body: Vec::new() })
+        }
+    }
+}
+
+/// An error which is converted into `503 Service Unavailable` with the `Retry-After` header.
+pub struct Busy(pub Duration);
+impl IntoErrorResponse for Busy {
+    type Response = ProblemResponse;
+    fn into_error_response(self) -> Self::Response {
+        let mut response = Problem::about_blank(HttpStatus::ServiceUnavailable).into_response();
+        response.set_retry_after(self.0);
+        response
+    }
+}
+
+/// Responds with the given name.
+#[derive(Clone)]
+pub struct NamedHelloHandler(pub &'static str);
+impl HandleRpc<Hello> for NamedHelloHandler {
+    type Future = FutureResult<HelloResponse, NeverFail>;
+    fn handle_rpc(self, _request: HelloRequest) -> Self::Future {
+        let body = self.0.as_bytes().to_owned();
+        futures::finished(HelloResponse::Ok { body })
+    }
+}
+
+/// Makes a builder of a server which listens on an ephemeral port of the loopback address.
+pub fn server_builder() -> RpcServerBuilder {
+    RpcServerBuilder::new(([127, 0, 0, 1], 0).into())
+}
+
+/// Returns an address on which no server is listening.
+pub fn unused_addr() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// A listener of which accept queue is full, so that connecting to it never completes.
+///
+/// NOTE: This is used instead of a non-routable address,
+/// because some environments (e.g., sandboxes with a transparent proxy) accept any address.
+pub struct UnresponsiveServer {
+    pub addr: SocketAddr,
+    _listener: net::TcpListener,
+    _streams: Vec<net::TcpStream>,
+}
+
+/// Starts a listener which never accepts connections, and fills its accept queue.
+pub fn unresponsive_server() -> UnresponsiveServer {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut streams = Vec::new();
+    while let Ok(stream) = net::TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+        streams.push(stream);
+        assert!(streams.len() < 10_000, "The accept queue is never filled");
+    }
+    UnresponsiveServer {
+        addr,
+        _listener: listener,
+        _streams: streams,
+    }
+}
+
+/// A server running on a background thread.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub handle: RpcServerHandle,
+    stopped: mpsc::Receiver<()>,
+}
+impl TestServer {
+    /// Waits until the server stops, and returns `false` if `timeout` expires.
+    pub fn wait_stopped(&self, timeout: Duration) -> bool {
+        self.stopped.recv_timeout(timeout).is_ok()
+    }
+}
+
+/// Starts the server built by `builder` on a background thread,
+/// and waits until it is ready to accept connections.
+pub fn spawn_server(builder: RpcServerBuilder) -> TestServer {
+    let (handle_tx, handle_rx) = mpsc::channel();
+    let (stopped_tx, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        let mut executor = ThreadPoolExecutor::new().unwrap();
+        let server = builder.start(executor.handle());
+        handle_tx.send(server.handle()).unwrap();
+        let monitor = executor.handle().spawn_monitor(server);
+        let result = executor.run_future(monitor).unwrap();
+        result.unwrap_or_else(|e| panic!("{:?}", e));
+        let _ = stopped_tx.send(());
+    });
+
+    let handle: RpcServerHandle = handle_rx.recv().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(addr) = handle.local_addr() {
+            return TestServer {
+                addr,
+                handle,
+                stopped,
+            };
+        }
+        assert!(Instant::now() < deadline, "The server did not start");
+        thread::sleep(Duration::from_millis(1));
+    }
+}